@@ -1,6 +1,9 @@
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server};
 use nowplaying_ttv_lib::errors::ErrorKind;
+use nowplaying_ttv_lib::layers::ConfigSource;
+use nowplaying_ttv_lib::reload::Reloader;
+use nowplaying_ttv_lib::sessions::{self, ExportFormat};
 use nowplaying_ttv_lib::{errors, spotify, twitch, Config, ServerStatus};
 use serde::Serialize;
 use std::collections::HashMap;
@@ -17,6 +20,14 @@ pub struct CallbackResponse {
     pub ack: bool,
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionSummary {
+    pub id: String,
+    pub channel: String,
+    pub started_at: i64,
+    pub songs: usize,
+}
+
 #[derive(Debug, Clone, Default, Serialize)]
 pub struct Status {
     pub cpu_usage: f32,
@@ -30,6 +41,7 @@ pub struct Api {
     pub callback_response: Option<Arc<Mutex<CallbackResponse>>>,
     pub callback_completed: Arc<Mutex<Arc<Notify>>>,
     pub config: Arc<Mutex<Config>>,
+    pub reloader: Arc<Reloader>,
     pub status: Arc<(Mutex<ServerStatus>, Notify)>,
     pub system_status: Arc<Mutex<Status>>,
     port: u16,
@@ -69,17 +81,25 @@ pub async fn hyper_server(api: Arc<Api>) -> NPResult<()> {
 
     tracing::info!("REST API running on http://{}", addr);
 
-    let twitch_oauth_url = twitch::make_oauth_url(
-        &api.config.lock().await.twitch_client_id,
-        twitch::CALLBACK_URI,
-    );
+    let (twitch_oauth_url, chat_username, broadcaster) = {
+        let conf = api.config.lock().await;
+        (
+            twitch::make_oauth_url(&conf.twitch_client_id, twitch::CALLBACK_URI),
+            conf.chat_username(),
+            conf.twitch_username.clone(),
+        )
+    };
 
     println!(
-        "Please visit this URL to authenticate with Twitch: {}",
-        twitch_oauth_url
+        "Please visit this URL logged in to Twitch as {} to authenticate the bot: {}",
+        chat_username, twitch_oauth_url
     );
 
     if api.config.lock().await.spotify_enabled {
+        println!(
+            "Spotify is authenticated with the account of {}.",
+            broadcaster
+        );
         let spotify_oauth_url = spotify::make_oauth_url(
             &api.config
                 .lock()
@@ -116,6 +136,7 @@ impl Api {
         callback_response: Option<Arc<Mutex<CallbackResponse>>>,
         callback_completed: Arc<Mutex<Arc<Notify>>>,
         config: Arc<Mutex<Config>>,
+        reloader: Arc<Reloader>,
         status: Arc<(Mutex<ServerStatus>, Notify)>,
         port: Option<u16>,
     ) -> Self {
@@ -152,6 +173,7 @@ impl Api {
             callback_response,
             callback_completed,
             config,
+            reloader,
             status,
             system_status,
             port: port.unwrap_or(3000),
@@ -184,7 +206,7 @@ impl Api {
 
             ("/config", &hyper::Method::POST) => {
                 let body = hyper::body::to_bytes(req.into_body()).await?;
-                let new_config = match serde_json::from_slice(&body) {
+                let new_config: Config = match serde_json::from_slice(&body) {
                     Ok(c) => c,
                     Err(e) => {
                         tracing::error!("Failed to parse config: {}", e);
@@ -196,12 +218,23 @@ impl Api {
                     }
                 };
 
-                *self.config.lock().await = new_config;
-                let response = Response::builder()
-                    .status(200)
-                    .body(Body::empty())
-                    .expect("Failed to build response");
-                response
+                match new_config.validate() {
+                    Ok(_) => {
+                        self.reloader.apply(new_config, ConfigSource::Api).await;
+                        Response::builder()
+                            .status(200)
+                            .body(Body::empty())
+                            .expect("Failed to build response")
+                    }
+                    Err(errors) => {
+                        tracing::error!("Rejected config: {}", errors::Error::from(errors.clone()));
+                        Response::builder()
+                            .status(422)
+                            .header("Content-Type", "application/json")
+                            .body(Body::from(serde_json::to_string(&errors).unwrap()))
+                            .expect("Failed to build response in config POST")
+                    }
+                }
             }
 
             ("/config", &hyper::Method::GET) => {
@@ -214,7 +247,16 @@ impl Api {
                 response
             }
 
-            ("/config", &hyper::Method::OPTIONS) => {
+            ("/config/sources", &hyper::Method::GET) => {
+                let config = self.config.lock().await;
+                Response::builder()
+                    .status(200)
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(serde_json::to_string(&config.report).unwrap()))
+                    .expect("Failed to build response")
+            }
+
+            ("/config", &hyper::Method::OPTIONS) | ("/config/sources", &hyper::Method::OPTIONS) => {
                 let response = Response::builder()
                     .status(200)
                     .body(Body::empty())
@@ -223,8 +265,8 @@ impl Api {
             }
 
             ("/saveconfig", &hyper::Method::POST) => {
-                let config = self.config.lock().await;
-                match config.save_to_file() {
+                let config = self.config.lock().await.clone();
+                match config.save().await {
                     Ok(_) => {
                         let response = Response::builder()
                             .status(200)
@@ -257,7 +299,38 @@ impl Api {
                 response
             }
 
-            ("/status", &hyper::Method::OPTIONS) => {
+            ("/sessions", &hyper::Method::GET) => match sessions::list() {
+                Ok(list) => {
+                    let list: Vec<SessionSummary> = list
+                        .iter()
+                        .map(|s| SessionSummary {
+                            id: s.id(),
+                            channel: s.channel.clone(),
+                            started_at: s.started_at,
+                            songs: s.tracks.len(),
+                        })
+                        .collect();
+
+                    Response::builder()
+                        .status(200)
+                        .header("Content-Type", "application/json")
+                        .body(Body::from(serde_json::to_string(&list).unwrap()))
+                        .expect("Failed to build response")
+                }
+                Err(e) => {
+                    tracing::error!("Failed to list sessions: {}", e);
+                    Response::builder()
+                        .status(500)
+                        .body(Body::empty())
+                        .expect("Failed to build response")
+                }
+            },
+
+            ("/sessions/export", &hyper::Method::GET) => self.export_session(&req),
+
+            ("/status", &hyper::Method::OPTIONS)
+            | ("/sessions", &hyper::Method::OPTIONS)
+            | ("/sessions/export", &hyper::Method::OPTIONS) => {
                 let response = Response::builder()
                     .status(200)
                     .body(Body::empty())
@@ -303,6 +376,68 @@ impl Api {
         }
     }
 
+    // ?session=<id>&channel=<login>&format=<csv|json|text>, all optional
+    fn export_session(&self, req: &Request<Body>) -> Response<Body> {
+        let mut id = None;
+        let mut channel = None;
+        let mut format = Ok(ExportFormat::Json);
+
+        for param in req.uri().query().unwrap_or("").split('&') {
+            let key_value: Vec<_> = param.splitn(2, '=').collect();
+            if key_value.len() == 2 {
+                match key_value[0] {
+                    "session" => id = Some(key_value[1]),
+                    "channel" => channel = Some(key_value[1]),
+                    "format" => format = key_value[1].parse::<ExportFormat>(),
+                    _ => {}
+                }
+            }
+        }
+
+        let format = match format {
+            Ok(format) => format,
+            Err(e) => {
+                return Response::builder()
+                    .status(400)
+                    .body(Body::from(e.message))
+                    .expect("Failed to build response in session export")
+            }
+        };
+
+        let session = match sessions::find(id, channel) {
+            Ok(session) => session,
+            Err(e) => {
+                return Response::builder()
+                    .status(404)
+                    .body(Body::from(e.message))
+                    .expect("Failed to build response in session export")
+            }
+        };
+
+        match session.export(format) {
+            Ok(exported) => Response::builder()
+                .status(200)
+                .header("Content-Type", format.content_type())
+                .header(
+                    "Content-Disposition",
+                    format!(
+                        "attachment; filename=\"{}.{}\"",
+                        session.id(),
+                        format.extension()
+                    ),
+                )
+                .body(Body::from(exported))
+                .expect("Failed to build response in session export"),
+            Err(e) => {
+                tracing::error!("Failed to export session {}: {}", session.id(), e);
+                Response::builder()
+                    .status(500)
+                    .body(Body::empty())
+                    .expect("Failed to build response in session export")
+            }
+        }
+    }
+
     async fn twitch_callback(&self, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
         let cr = match self.callback_response {
             Some(ref cr) => cr,
@@ -363,6 +498,19 @@ impl Api {
                 }
             };
 
+            let login = match twitch::validate_access_token(&twitch_auth.access_token).await {
+                Ok(v) => v.login,
+                Err(e) => {
+                    tracing::error!("Failed to check the new Twitch token: {}", e);
+                    return self.twitch_callback_error(req).await;
+                }
+            };
+
+            if let Err(e) = twitch::check_login(&*self.config.lock().await, &login) {
+                tracing::error!("{}", e.message);
+                return self.twitch_callback_error(req).await;
+            }
+
             cr.lock().await.twitch_auth.replace(twitch_auth);
 
             if self.config.lock().await.spotify_enabled {
@@ -7,10 +7,15 @@ use tokio::{
 use crate::api::CallbackResponse;
 use nowplaying_ttv_lib::{
     errors::{self, Error, ErrorKind, NPResult},
-    soundcloud, spotify, twitch, Config, ServerStatus,
+    layers::ConfigSource,
+    reload::Reloader,
+    sessions::{self, ExportFormat},
+    soundcloud,
+    sources::SourceRegistry,
+    spotify, twitch, Config, ServerStatus,
 };
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 mod api;
 
@@ -25,6 +30,56 @@ struct Args {
     internal_port: Option<u16>,
     #[clap(short, long)]
     config: Option<String>,
+    // never prompt on stdin, for containers and services
+    #[clap(long)]
+    non_interactive: bool,
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Load the config, report every problem with it and exit
+    CheckConfig,
+    /// List the recorded sessions, newest first
+    Sessions,
+    /// Export the songs played in a session
+    Export {
+        /// The session to export, the newest one when left out
+        session: Option<String>,
+        /// Export the newest session of this channel
+        #[clap(long)]
+        channel: Option<String>,
+        /// csv, json or text
+        #[clap(short, long, default_value = "text")]
+        format: ExportFormat,
+        /// Write to this file instead of stdout
+        #[clap(short, long)]
+        output: Option<String>,
+    },
+}
+
+fn load_config(non_interactive: bool) -> NPResult<Config> {
+    let path = Config::path()?;
+    tracing::info!("Loading config from {}", path);
+
+    match Config::from_file() {
+        Ok(mut c) => {
+            c.load_secrets().map_err(|e| {
+                Error::new(
+                    format!("Failed to load tokens. {}", e),
+                    ErrorKind::ConfigError,
+                )
+            })?;
+            Config::from_env(Some(c), non_interactive)
+        }
+        // a file that exists but cannot be read must not be replaced by one built from env
+        Err(e) if std::path::Path::new(&path).exists() => Err(Error::new(
+            format!("Failed to load config. {}", e),
+            ErrorKind::ConfigError,
+        )),
+        Err(_) => Config::from_env(None, non_interactive),
+    }
 }
 
 #[tokio::main]
@@ -38,15 +93,82 @@ async fn main() {
 
     tracing::info!("Starting nowplaying-ttv");
 
-    tracing::info!("Loading config");
-    let config = match Config::from_json() {
-        Ok(c) => Config::from_env(Some(c)),
-        Err(_) => Config::from_env(None),
+    let args = Args::parse();
+    let non_interactive = args.non_interactive || nowplaying_ttv_lib::non_interactive_from_env();
+
+    if let Some(path) = args.config.clone() {
+        Config::set_path(path);
+    }
+
+    if let Some(Commands::CheckConfig) = args.command {
+        // prompts would only get in the way of a check
+        let config = match load_config(true) {
+            Ok(c) => c,
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        };
+
+        match config.validate() {
+            Ok(_) => println!("{} is valid", Config::path().unwrap_or_default()),
+            Err(errors) => {
+                for e in errors {
+                    println!("{}", e);
+                }
+                std::process::exit(1);
+            }
+        }
+
+        return;
+    }
+
+    match args.command {
+        Some(Commands::Sessions) => {
+            if let Err(e) = list_sessions() {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Commands::Export {
+            ref session,
+            ref channel,
+            format,
+            ref output,
+        }) => {
+            if let Err(e) = export_session(
+                session.as_deref(),
+                channel.as_deref(),
+                format,
+                output.as_deref(),
+            ) {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        _ => {}
+    }
+
+    let mut config = match load_config(non_interactive) {
+        Ok(c) => c,
+        Err(e) => {
+            tracing::error!("{}", e);
+            return;
+        }
     };
 
-    let args = Args::parse();
+    if args.web_dashboard {
+        config.layer(ConfigSource::Cli, |c| c.web_dashboard_enabled = true);
+    }
+
+    if let Err(errors) = config.validate() {
+        tracing::error!("Invalid config. {}", Error::from(errors));
+        return;
+    }
 
-    if config.web_dashboard_enabled || args.web_dashboard {
+    if config.web_dashboard_enabled {
         tracing::info!("Starting web dashboard");
         let exec_path = match get_web_executable_path() {
             Ok(path) => path,
@@ -97,19 +219,22 @@ async fn main() {
 
     let config = Arc::new(Mutex::new(config));
 
-    let twitch = twitch::TwitchClient::new(config.clone(), None, None);
+    let reloader = Arc::new(Reloader::new(config.clone()));
+    tokio::spawn(reloader.clone().watch());
+
+    let twitch =
+        twitch::TwitchClient::new(config.clone(), SourceRegistry::default(), reloader.clone());
 
     tracing::info!("Checking Twitch OAuth");
 
-    let reauth = match twitch.test_token().await {
+    let twitch_reauth = match twitch.test_token().await {
         Ok(_) => false,
         Err(_) => {
             tracing::info!("Refreshing Twitch OAuth");
             match twitch.refresh_oauth().await {
                 Ok(oauth) => {
                     if let Some(oauth) = oauth {
-                        config.lock().await.twitch_oauth = Some(oauth.access_token.clone());
-                        config.lock().await.twitch_oauth_refresh = Some(oauth.refresh_token.clone());
+                        oauth.store(&mut *config.lock().await);
                     }
                     false
                 }
@@ -124,6 +249,27 @@ async fn main() {
         },
     };
 
+    // a token of the broadcaster is no use once a bot account is set
+    let twitch_reauth = twitch_reauth
+        || match twitch.check_account().await {
+            Ok(_) => false,
+            Err(e) => {
+                tracing::info!("{}, please reauthenticate.", e.message);
+                true
+            }
+        };
+
+    let spotify_reauth = {
+        let conf = config.lock().await;
+        conf.spotify_enabled && conf.spotify_oauth_refresh.is_none()
+    };
+
+    if spotify_reauth {
+        tracing::info!("No Spotify OAuth saved, please authenticate.");
+    }
+
+    let reauth = twitch_reauth || spotify_reauth;
+
     let status = Arc::new((Mutex::new(ServerStatus::Stopped), Notify::new()));
 
     let callback_completed = Arc::new(Mutex::new(Arc::new(Notify::new())));
@@ -139,6 +285,7 @@ async fn main() {
                 callback_response.clone(),
                 callback_completed.clone(),
                 config.clone(),
+                reloader.clone(),
                 status.clone(),
                 args.internal_port,
             )
@@ -157,6 +304,7 @@ async fn main() {
 
         let worker = twitch_listener_worker(
             config.clone(),
+            reloader.clone(),
             callback_response.clone(),
             callback_completed.clone(),
             status.clone(),
@@ -179,6 +327,39 @@ async fn main() {
     }
 }
 
+fn list_sessions() -> NPResult<()> {
+    let sessions = sessions::list()?;
+
+    if sessions.is_empty() {
+        println!(
+            "No sessions recorded in {}",
+            sessions::sessions_dir()?.display()
+        );
+    }
+
+    for session in sessions {
+        println!("{}\t{} songs", session.id(), session.tracks.len());
+    }
+
+    Ok(())
+}
+
+fn export_session(
+    id: Option<&str>,
+    channel: Option<&str>,
+    format: ExportFormat,
+    output: Option<&str>,
+) -> NPResult<()> {
+    let exported = sessions::find(id, channel)?.export(format)?;
+
+    match output {
+        Some(path) => std::fs::write(path, exported)?,
+        None => print!("{}", exported),
+    }
+
+    Ok(())
+}
+
 fn get_web_executable_path() -> NPResult<String> {
     let cargo_file = include_str!("../Cargo.toml");
 
@@ -217,6 +398,7 @@ fn get_web_executable_path() -> NPResult<String> {
 
 async fn twitch_listener_worker(
     config: Arc<Mutex<Config>>,
+    reloader: Arc<Reloader>,
     callback_response: Option<Arc<Mutex<CallbackResponse>>>,
     callback_completed: Arc<Mutex<Arc<Notify>>>,
     status: Arc<(Mutex<ServerStatus>, Notify)>,
@@ -238,50 +420,55 @@ async fn twitch_listener_worker(
         )
     }
 
-    let sc = soundcloud::SoundcloudClient::new(config.lock().await.soundcloud_oauth.clone())
-        .map(|client| Arc::new(client));
+    let sources = SourceRegistry::new(config.lock().await.source_fallback);
 
-    let mut spot = None;
+    if let Some(sc) = soundcloud::SoundcloudClient::from_config(&*config.lock().await) {
+        sources.register(Arc::new(sc));
+    }
+
+    let mut spotify_tokens = None;
 
     if let Some(cr) = callback_response.clone() {
-        if config.lock().await.spotify_enabled {
-            if let Some(spotify_auth) = cr.lock().await.spotify_auth.clone() {
-                let client_id = config
-                    .lock()
-                    .await
-                    .spotify_client_id
-                    .clone()
-                    .expect("SPOTIFY_CLIENT_ID is not set");
-
-                let secret = config
-                    .lock()
-                    .await
-                    .spotify_client_secret
-                    .clone()
-                    .expect("SPOTIFY_CLIENT_SECRET is not set");
-
-                config.lock().await.spotify_oauth = Some(spotify_auth.access_token.clone());
-                config.lock().await.spotify_oauth_refresh =
-                    Some(spotify_auth.refresh_token.clone());
-
-                spot = Some(Arc::new(Mutex::new(spotify::SpotifyClient::new(
-                    client_id,
-                    secret,
-                    spotify_auth.access_token,
-                    spotify_auth.refresh_token,
-                ))));
-            }
+        // applied once, a restarted worker must not put back tokens that were refreshed since
+        let (spotify_auth, twitch_auth) = {
+            let mut cr = cr.lock().await;
+            (cr.spotify_auth.take(), cr.twitch_auth.take())
         };
 
-        if let Some(twitch_auth) = cr.lock().await.twitch_auth.clone() {
-            config.lock().await.twitch_oauth = Some(twitch_auth.access_token.clone());
-            config.lock().await.twitch_oauth_refresh = Some(twitch_auth.refresh_token.clone());
+        if let Some(spotify_auth) = spotify_auth {
+            spotify_tokens = Some(spotify::SpotifyTokens::new(
+                spotify_auth.access_token,
+                spotify_auth.refresh_token,
+                Some(spotify_auth.expires_in),
+            ));
+        }
+
+        if let Some(twitch_auth) = twitch_auth {
+            twitch_auth.store(&mut *config.lock().await);
+        }
+    }
+
+    {
+        let mut conf = config.lock().await;
+
+        if let Some(tokens) = &spotify_tokens {
+            conf.layer(ConfigSource::Runtime, |c| {
+                c.spotify_oauth = Some(tokens.access_token.clone());
+                c.spotify_oauth_refresh = Some(tokens.refresh_token.clone());
+            });
+        }
+
+        if let Some(sp) =
+            spotify::SpotifyClient::from_config(config.clone(), &conf, spotify_tokens, None)
+        {
+            sources.register(Arc::new(sp));
         }
     }
 
-    let mut twitch = twitch::TwitchClient::new(config.clone(), sc, spot);
+    let mut twitch = twitch::TwitchClient::new(config.clone(), sources, reloader);
 
-    if let Err(_) = config.lock().await.save_to_file() {
+    let snapshot = config.lock().await.clone();
+    if let Err(_) = snapshot.save().await {
         tracing::error!("Failed to save config.");
     }
 
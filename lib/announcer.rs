@@ -0,0 +1,121 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+
+use crate::track::TrackMetadata;
+use crate::tracker::Tracker;
+use crate::twitch::IrcClient;
+use crate::{template, Config};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AnnounceConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // announcements closer together than this are held back until the gap has passed
+    #[serde(default = "default_min_gap_secs")]
+    pub min_gap_secs: u64,
+    #[serde(default = "default_template")]
+    pub template: String,
+}
+
+fn default_min_gap_secs() -> u64 {
+    60
+}
+
+fn default_template() -> String {
+    String::from("Now playing: {artist} - {title} {url}")
+}
+
+impl Default for AnnounceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_gap_secs: default_min_gap_secs(),
+            template: default_template(),
+        }
+    }
+}
+
+pub async fn run(
+    config: Arc<Mutex<Config>>,
+    tracker: Arc<Tracker>,
+    client: Arc<IrcClient>,
+    channel: String,
+) {
+    let mut changes = tracker.subscribe();
+
+    // whatever is playing when the bot starts is only remembered, so restarts do not announce again
+    let mut last_announced: Option<TrackMetadata> = tracker.first_poll().await;
+    let mut last_announced_at: Option<Instant> = None;
+    let mut pending: Option<TrackMetadata> = None;
+
+    loop {
+        // read every time, the settings can change while running
+        let conf = config.lock().await.for_channel(&channel).announce;
+        let min_gap = Duration::from_secs(conf.min_gap_secs);
+
+        let wait = last_announced_at
+            .map(|at| min_gap.saturating_sub(at.elapsed()))
+            .unwrap_or_default();
+
+        tokio::select! {
+            change = changes.recv() => match change {
+                Ok(track) => pending = Some(track),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            },
+            _ = tokio::time::sleep(wait), if pending.is_some() => {}
+        }
+
+        let track = match pending.take() {
+            Some(track) => track,
+            None => continue,
+        };
+
+        if !conf.enabled {
+            last_announced = Some(track);
+            continue;
+        }
+
+        if last_announced
+            .as_ref()
+            .is_some_and(|last| last.same_track(&track))
+        {
+            continue;
+        }
+
+        if let Some(at) = last_announced_at {
+            if at.elapsed() < min_gap {
+                tracing::debug!("Holding back announcement of {}, too soon", track.title);
+                pending = Some(track);
+                continue;
+            }
+        }
+
+        let message = match template::render(&conf.template, Some(&track), "") {
+            Ok(message) => message,
+            Err(e) => {
+                tracing::error!("Failed to render announcement: {}", e);
+                continue;
+            }
+        };
+
+        tracing::info!(
+            "Announcing {} - {} in {}",
+            track.artist(),
+            track.title,
+            channel
+        );
+
+        if let Err(e) = client.say(channel.clone(), message).await {
+            tracing::error!("Failed to send announcement: {:?}", e);
+            continue;
+        }
+
+        last_announced = Some(track);
+        last_announced_at = Some(Instant::now());
+    }
+}
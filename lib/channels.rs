@@ -0,0 +1,207 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::announcer::AnnounceConfig;
+use crate::commands::CommandConfig;
+use crate::cooldown::{CooldownConfig, Cooldowns};
+use crate::history::{History, HistoryConfig};
+use crate::playback::{VoteSkip, VoteSkipConfig};
+use crate::requests::{SongRequestConfig, SongRequests};
+use crate::sessions::SessionRecorder;
+use crate::soundcloud::SoundcloudClient;
+use crate::sources::SourceRegistry;
+use crate::spotify::SpotifyClient;
+use crate::tracker::Tracker;
+use crate::Config;
+
+// settings a channel leaves out are taken from the top level of the config
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ChannelConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub soundcloud_enabled: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub soundcloud_oauth: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spotify_enabled: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spotify_client_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spotify_client_secret: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spotify_oauth: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spotify_oauth_refresh: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_order: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_fallback: Option<bool>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub now_playing_template: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nothing_playing_template: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commands: Option<CommandConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cooldowns: Option<CooldownConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub announce: Option<AnnounceConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub song_requests: Option<SongRequestConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vote_skip: Option<VoteSkipConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history: Option<HistoryConfig>,
+}
+
+impl ChannelConfig {
+    // channels that change none of these read the shared music sources
+    pub fn has_own_sources(&self) -> bool {
+        self.soundcloud_enabled.is_some()
+            || self.soundcloud_oauth.is_some()
+            || self.spotify_enabled.is_some()
+            || self.spotify_client_id.is_some()
+            || self.spotify_client_secret.is_some()
+            || self.spotify_oauth.is_some()
+            || self.spotify_oauth_refresh.is_some()
+            || self.source_order.is_some()
+            || self.source_fallback.is_some()
+    }
+
+    pub fn apply(&self, config: &mut Config) {
+        fn set<T: Clone>(field: &mut T, value: &Option<T>) {
+            if let Some(value) = value {
+                *field = value.clone();
+            }
+        }
+
+        fn set_some<T: Clone>(field: &mut Option<T>, value: &Option<T>) {
+            if value.is_some() {
+                *field = value.clone();
+            }
+        }
+
+        set(&mut config.soundcloud_enabled, &self.soundcloud_enabled);
+        set_some(&mut config.soundcloud_oauth, &self.soundcloud_oauth);
+        set(&mut config.spotify_enabled, &self.spotify_enabled);
+        set_some(&mut config.spotify_client_id, &self.spotify_client_id);
+        set_some(
+            &mut config.spotify_client_secret,
+            &self.spotify_client_secret,
+        );
+        set_some(&mut config.spotify_oauth, &self.spotify_oauth);
+        set_some(
+            &mut config.spotify_oauth_refresh,
+            &self.spotify_oauth_refresh,
+        );
+        set(&mut config.source_order, &self.source_order);
+        set(&mut config.source_fallback, &self.source_fallback);
+        set(&mut config.now_playing_template, &self.now_playing_template);
+        set(
+            &mut config.nothing_playing_template,
+            &self.nothing_playing_template,
+        );
+        set(&mut config.commands, &self.commands);
+        set(&mut config.cooldowns, &self.cooldowns);
+        set(&mut config.announce, &self.announce);
+        set(&mut config.song_requests, &self.song_requests);
+        set(&mut config.vote_skip, &self.vote_skip);
+        set(&mut config.history, &self.history);
+    }
+}
+
+// twitch logins are lowercase letters, digits and underscores
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+// a joined channel, the tracker is shared with the other channels unless it has its own sources
+#[derive(Debug)]
+pub struct Channel {
+    pub name: String,
+    pub tracker: Arc<Tracker>,
+    pub cooldowns: Mutex<Cooldowns>,
+    pub requests: Arc<SongRequests>,
+    pub votes: Mutex<VoteSkip>,
+    pub history: Arc<History>,
+    pub session: Arc<SessionRecorder>,
+    own_sources: bool,
+}
+
+impl Channel {
+    pub fn new(
+        name: &str,
+        config: &Arc<Mutex<Config>>,
+        conf: &Config,
+        shared: &Arc<Tracker>,
+    ) -> Self {
+        let own_sources = conf
+            .channels
+            .get(name)
+            .is_some_and(ChannelConfig::has_own_sources);
+
+        let tracker = if own_sources {
+            let sources = sources_for(config, &conf.for_channel(name), Some(name));
+            if sources.is_empty() {
+                tracing::warn!("Channel {} has no music sources enabled", name);
+            }
+            Arc::new(Tracker::new(sources))
+        } else {
+            shared.clone()
+        };
+
+        Self {
+            name: name.to_string(),
+            tracker,
+            cooldowns: Mutex::new(Cooldowns::default()),
+            requests: Arc::new(SongRequests::default()),
+            votes: Mutex::new(VoteSkip::default()),
+            history: Arc::new(History::open(history_path(name))),
+            session: Arc::new(SessionRecorder::new(name)),
+            own_sources,
+        }
+    }
+
+    pub fn has_own_sources(&self) -> bool {
+        self.own_sources
+    }
+}
+
+// next to the config file, None keeps the history in memory
+fn history_path(channel: &str) -> Option<PathBuf> {
+    match Config::dir() {
+        Ok(dir) => Some(dir.join(format!("history-{}.json", channel))),
+        Err(e) => {
+            tracing::warn!("The play history of {} will not be saved: {}", channel, e);
+            None
+        }
+    }
+}
+
+// conf is the effective config of the channel, see Config::for_channel
+pub fn sources_for(
+    config: &Arc<Mutex<Config>>,
+    conf: &Config,
+    channel: Option<&str>,
+) -> SourceRegistry {
+    let sources = SourceRegistry::new(conf.source_fallback);
+
+    if let Some(sc) = SoundcloudClient::from_config(conf) {
+        sources.register(Arc::new(sc));
+    }
+
+    if let Some(sp) = SpotifyClient::from_config(config.clone(), conf, None, channel) {
+        sources.register(Arc::new(sp));
+    }
+
+    sources
+}
@@ -0,0 +1,352 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use twitch_irc::message::Badge;
+
+use crate::errors::FieldError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandAction {
+    NowPlaying,
+    SongRequest,
+    Queue,
+    Skip,
+    Pause,
+    Resume,
+    Volume,
+    VoteSkip,
+    LastSong,
+    History,
+}
+
+// ordered from least to most privileged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Viewer,
+    Subscriber,
+    Vip,
+    Moderator,
+    Broadcaster,
+}
+
+impl Role {
+    pub fn from_badges(badges: &[Badge]) -> Self {
+        badges
+            .iter()
+            .map(|b| match b.name.as_str() {
+                "broadcaster" => Role::Broadcaster,
+                "moderator" => Role::Moderator,
+                "vip" => Role::Vip,
+                "subscriber" | "founder" => Role::Subscriber,
+                _ => Role::Viewer,
+            })
+            .max()
+            .unwrap_or(Role::Viewer)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Command {
+    pub action: CommandAction,
+    pub trigger: String,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    // the least privileged role allowed to use the command
+    #[serde(default = "default_permission")]
+    pub permission: Role,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CommandConfig {
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    #[serde(default = "default_case_insensitive")]
+    pub case_insensitive: bool,
+    #[serde(default = "default_commands")]
+    pub commands: Vec<Command>,
+    // sent when a user lacks the permission for a command, empty ignores them silently
+    #[serde(default)]
+    pub permission_denied_reply: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedCommand {
+    pub action: CommandAction,
+    pub trigger: String,
+    pub args: Vec<String>,
+    pub permission: Role,
+}
+
+fn default_prefix() -> String {
+    String::from("!")
+}
+
+fn default_case_insensitive() -> bool {
+    true
+}
+
+fn default_permission() -> Role {
+    Role::Viewer
+}
+
+// the song request commands do nothing until song_requests.enabled is set
+fn default_commands() -> Vec<Command> {
+    let moderator = |action, trigger: &str| Command {
+        action,
+        trigger: trigger.to_string(),
+        aliases: Vec::new(),
+        permission: Role::Moderator,
+    };
+
+    vec![
+        Command {
+            action: CommandAction::NowPlaying,
+            trigger: String::from("np"),
+            aliases: vec![String::from("song")],
+            permission: default_permission(),
+        },
+        Command {
+            action: CommandAction::SongRequest,
+            trigger: String::from("sr"),
+            aliases: Vec::new(),
+            permission: default_permission(),
+        },
+        Command {
+            action: CommandAction::Queue,
+            trigger: String::from("queue"),
+            aliases: Vec::new(),
+            permission: default_permission(),
+        },
+        moderator(CommandAction::Skip, "skip"),
+        moderator(CommandAction::Pause, "pause"),
+        moderator(CommandAction::Resume, "resume"),
+        moderator(CommandAction::Volume, "volume"),
+        Command {
+            action: CommandAction::VoteSkip,
+            trigger: String::from("voteskip"),
+            aliases: Vec::new(),
+            permission: default_permission(),
+        },
+        Command {
+            action: CommandAction::LastSong,
+            trigger: String::from("lastsong"),
+            aliases: Vec::new(),
+            permission: default_permission(),
+        },
+        Command {
+            action: CommandAction::History,
+            trigger: String::from("history"),
+            aliases: Vec::new(),
+            permission: default_permission(),
+        },
+    ]
+}
+
+impl Default for CommandConfig {
+    fn default() -> Self {
+        Self {
+            prefix: default_prefix(),
+            case_insensitive: default_case_insensitive(),
+            commands: default_commands(),
+            permission_denied_reply: String::new(),
+        }
+    }
+}
+
+impl Command {
+    fn triggers(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.trigger).chain(self.aliases.iter())
+    }
+}
+
+impl CommandConfig {
+    // only the first word is matched, everything after it is passed on as arguments
+    pub fn parse(&self, message: &str) -> Option<ParsedCommand> {
+        let mut words = message.split_whitespace();
+        let first = words.next()?;
+        let trigger = first.strip_prefix(self.prefix.as_str())?;
+
+        let command = self.commands.iter().find(|c| {
+            c.triggers().any(|t| {
+                if self.case_insensitive {
+                    t.eq_ignore_ascii_case(trigger)
+                } else {
+                    t == trigger
+                }
+            })
+        })?;
+
+        Some(ParsedCommand {
+            action: command.action,
+            trigger: trigger.to_string(),
+            args: words.map(String::from).collect(),
+            permission: command.permission,
+        })
+    }
+
+    pub fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        let mut seen = HashSet::new();
+
+        if self.prefix.contains(char::is_whitespace) {
+            errors.push(FieldError::new(
+                "commands.prefix",
+                String::from("must not contain spaces"),
+            ));
+        }
+
+        for (i, command) in self.commands.iter().enumerate() {
+            let field = format!("commands.commands[{}]", i);
+
+            for trigger in command.triggers() {
+                if trigger.is_empty() || trigger.contains(char::is_whitespace) {
+                    errors.push(FieldError::new(
+                        &field,
+                        format!(
+                            "Command trigger \"{}\" must be a single non empty word",
+                            trigger
+                        ),
+                    ));
+                    continue;
+                }
+
+                let key = if self.case_insensitive {
+                    trigger.to_lowercase()
+                } else {
+                    trigger.clone()
+                };
+
+                if !seen.insert(key) {
+                    errors.push(FieldError::new(
+                        &field,
+                        format!("Command trigger \"{}\" is used more than once", trigger),
+                    ));
+                }
+            }
+        }
+
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(action: CommandAction, trigger: &str, aliases: &[&str]) -> Command {
+        Command {
+            action,
+            trigger: trigger.to_string(),
+            aliases: aliases.iter().map(|a| a.to_string()).collect(),
+            permission: Role::Viewer,
+        }
+    }
+
+    #[test]
+    fn parses_trigger_and_args() {
+        let config = CommandConfig::default();
+        let parsed = config.parse("!sr never gonna give").unwrap();
+
+        assert_eq!(parsed.action, CommandAction::SongRequest);
+        assert_eq!(parsed.trigger, "sr");
+        assert_eq!(parsed.args, vec!["never", "gonna", "give"]);
+        assert_eq!(parsed.permission, Role::Viewer);
+    }
+
+    #[test]
+    fn parses_aliases() {
+        let config = CommandConfig::default();
+
+        assert_eq!(
+            config.parse("!song").unwrap().action,
+            CommandAction::NowPlaying
+        );
+    }
+
+    #[test]
+    fn matches_only_the_first_word() {
+        let config = CommandConfig::default();
+
+        assert!(config.parse("what is the !np").is_none());
+        assert!(config.parse("!nope").is_none());
+        assert!(config.parse("").is_none());
+    }
+
+    #[test]
+    fn needs_the_prefix() {
+        let mut config = CommandConfig::default();
+        assert!(config.parse("np").is_none());
+
+        config.prefix = String::from("?");
+        assert!(config.parse("!np").is_none());
+        assert!(config.parse("?np").is_some());
+    }
+
+    #[test]
+    fn case_sensitivity_follows_the_config() {
+        let mut config = CommandConfig::default();
+        assert!(config.parse("!NP").is_some());
+
+        config.case_insensitive = false;
+        assert!(config.parse("!NP").is_none());
+        assert!(config.parse("!np").is_some());
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        assert!(CommandConfig::default().validate().is_empty());
+    }
+
+    #[test]
+    fn rejects_prefix_with_spaces() {
+        let config = CommandConfig {
+            prefix: String::from("! "),
+            ..Default::default()
+        };
+
+        let errors = config.validate();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "commands.prefix");
+    }
+
+    #[test]
+    fn rejects_empty_and_multi_word_triggers() {
+        let config = CommandConfig {
+            commands: vec![
+                command(CommandAction::NowPlaying, "", &[]),
+                command(CommandAction::Queue, "up next", &[]),
+            ],
+            ..Default::default()
+        };
+
+        let errors = config.validate();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].field, "commands.commands[0]");
+        assert_eq!(errors[1].field, "commands.commands[1]");
+    }
+
+    #[test]
+    fn rejects_duplicate_triggers() {
+        let commands = vec![
+            command(CommandAction::NowPlaying, "np", &["song"]),
+            command(CommandAction::SongRequest, "sr", &["Song"]),
+        ];
+
+        let insensitive = CommandConfig {
+            commands: commands.clone(),
+            ..Default::default()
+        };
+        let errors = insensitive.validate();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "commands.commands[1]");
+
+        let sensitive = CommandConfig {
+            case_insensitive: false,
+            commands,
+            ..Default::default()
+        };
+        assert!(sensitive.validate().is_empty());
+    }
+}
@@ -0,0 +1,94 @@
+use serde::Serialize;
+use toml_edit::{Document, Item, Table};
+
+use crate::errors::{Error, ErrorKind, NPResult};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    // files without an extension are read as JSON like before
+    pub fn from_path(path: &str) -> NPResult<Self> {
+        let extension = std::path::Path::new(path)
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase());
+
+        match extension.as_deref() {
+            None | Some("json") => Ok(Self::Json),
+            Some("toml") => Ok(Self::Toml),
+            Some("yaml") | Some("yml") => Ok(Self::Yaml),
+            Some(other) => Err(Error::new(
+                format!(
+                    "Unknown config file extension .{}, use .json, .toml or .yaml",
+                    other
+                ),
+                ErrorKind::ConfigError,
+            )),
+        }
+    }
+
+    pub fn parse(&self, text: &str) -> NPResult<serde_json::Value> {
+        let parse_error = |e: String| Error::new(e, ErrorKind::ParseError);
+
+        match self {
+            Self::Json => Ok(serde_json::from_str(text)?),
+            Self::Toml => toml::from_str(text).map_err(|e| parse_error(e.to_string())),
+            Self::Yaml => serde_yaml::from_str(text).map_err(|e| parse_error(e.to_string())),
+        }
+    }
+
+    // existing is the current file contents, TOML keeps its comments and layout where the keys still exist
+    pub fn render<T: Serialize>(&self, value: &T, existing: Option<&str>) -> NPResult<String> {
+        let render_error = |e: String| Error::new(e, ErrorKind::ParseError);
+
+        match self {
+            Self::Json => Ok(serde_json::to_string_pretty(value)?),
+            Self::Yaml => serde_yaml::to_string(value).map_err(|e| render_error(e.to_string())),
+            Self::Toml => {
+                // toml writes nested structs as [sections] like people do by hand
+                let new = toml::to_string(value)
+                    .map_err(|e| render_error(e.to_string()))?
+                    .parse::<Document>()
+                    .map_err(|e| render_error(e.to_string()))?;
+
+                let mut doc = match existing.and_then(|text| text.parse::<Document>().ok()) {
+                    Some(doc) => doc,
+                    None => return Ok(new.to_string()),
+                };
+
+                merge_table(doc.as_table_mut(), new.as_table());
+
+                Ok(doc.to_string())
+            }
+        }
+    }
+}
+
+fn merge_table(existing: &mut Table, new: &Table) {
+    let removed: Vec<String> = existing
+        .iter()
+        .filter(|(key, _)| !new.contains_key(key))
+        .map(|(key, _)| key.to_string())
+        .collect();
+    for key in removed {
+        existing.remove(&key);
+    }
+
+    for (key, item) in new.iter() {
+        match (existing.get_mut(key), item) {
+            (Some(Item::Table(old)), Item::Table(new)) => merge_table(old, new),
+            (Some(Item::Value(old)), Item::Value(new)) => {
+                let decor = old.decor().clone();
+                *old = new.clone();
+                *old.decor_mut() = decor;
+            }
+            _ => {
+                existing.insert(key, item.clone());
+            }
+        }
+    }
+}
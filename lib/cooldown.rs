@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::commands::{CommandAction, Role};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CooldownConfig {
+    // seconds between two uses of the same command by anyone
+    #[serde(default = "default_global_secs")]
+    pub global_secs: u64,
+    // seconds between two uses of the same command by the same user
+    #[serde(default = "default_user_secs")]
+    pub user_secs: u64,
+    #[serde(default = "default_exempt")]
+    pub exempt: Vec<Role>,
+}
+
+fn default_global_secs() -> u64 {
+    5
+}
+
+fn default_user_secs() -> u64 {
+    30
+}
+
+fn default_exempt() -> Vec<Role> {
+    vec![Role::Broadcaster, Role::Moderator, Role::Vip]
+}
+
+impl Default for CooldownConfig {
+    fn default() -> Self {
+        Self {
+            global_secs: default_global_secs(),
+            user_secs: default_user_secs(),
+            exempt: default_exempt(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CooldownKind {
+    Global,
+    User,
+}
+
+#[derive(Debug, Default)]
+pub struct Cooldowns {
+    global: HashMap<CommandAction, Instant>,
+    user: HashMap<(CommandAction, String), Instant>,
+    // users told they lack the permission for a command
+    denied: HashMap<String, Instant>,
+}
+
+impl Cooldowns {
+    // records the use when it is allowed, otherwise returns which cooldown is active and for how long
+    pub fn try_use(
+        &mut self,
+        config: &CooldownConfig,
+        action: CommandAction,
+        user: &str,
+        role: Role,
+    ) -> Result<(), (CooldownKind, Duration)> {
+        if config.exempt.contains(&role) {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let global_cd = Duration::from_secs(config.global_secs);
+        let user_cd = Duration::from_secs(config.user_secs);
+
+        self.user
+            .retain(|_, last| now.duration_since(*last) < user_cd);
+
+        if let Some(last) = self.global.get(&action) {
+            let elapsed = now.duration_since(*last);
+            if elapsed < global_cd {
+                return Err((CooldownKind::Global, global_cd - elapsed));
+            }
+        }
+
+        let key = (action, user.to_lowercase());
+        if let Some(last) = self.user.get(&key) {
+            return Err((CooldownKind::User, user_cd - now.duration_since(*last)));
+        }
+
+        self.global.insert(action, now);
+        if !user_cd.is_zero() {
+            self.user.insert(key, now);
+        }
+
+        Ok(())
+    }
+
+    // whether to reply to a user that lacks the permission for a command, at most once per user
+    // cooldown so spamming a command does not flood chat
+    pub fn try_deny(&mut self, config: &CooldownConfig, user: &str) -> bool {
+        let now = Instant::now();
+        let user_cd = Duration::from_secs(config.user_secs);
+
+        self.denied
+            .retain(|_, last| now.duration_since(*last) < user_cd);
+
+        let user = user.to_lowercase();
+        if self.denied.contains_key(&user) {
+            return false;
+        }
+
+        if !user_cd.is_zero() {
+            self.denied.insert(user, now);
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(global_secs: u64, user_secs: u64) -> CooldownConfig {
+        CooldownConfig {
+            global_secs,
+            user_secs,
+            exempt: default_exempt(),
+        }
+    }
+
+    #[test]
+    fn global_cooldown_blocks_everyone() {
+        let config = config(60, 0);
+        let mut cooldowns = Cooldowns::default();
+
+        assert!(cooldowns
+            .try_use(&config, CommandAction::NowPlaying, "alice", Role::Viewer)
+            .is_ok());
+
+        match cooldowns.try_use(&config, CommandAction::NowPlaying, "bob", Role::Viewer) {
+            Err((CooldownKind::Global, left)) => assert!(left <= Duration::from_secs(60)),
+            other => panic!("expected the global cooldown, got {:?}", other),
+        }
+
+        // other commands have their own cooldown
+        assert!(cooldowns
+            .try_use(&config, CommandAction::Queue, "bob", Role::Viewer)
+            .is_ok());
+    }
+
+    #[test]
+    fn user_cooldown_blocks_the_same_user() {
+        let config = config(0, 60);
+        let mut cooldowns = Cooldowns::default();
+
+        assert!(cooldowns
+            .try_use(&config, CommandAction::NowPlaying, "alice", Role::Viewer)
+            .is_ok());
+        assert!(matches!(
+            cooldowns.try_use(&config, CommandAction::NowPlaying, "Alice", Role::Viewer),
+            Err((CooldownKind::User, _))
+        ));
+        assert!(cooldowns
+            .try_use(&config, CommandAction::NowPlaying, "bob", Role::Viewer)
+            .is_ok());
+        assert!(cooldowns
+            .try_use(&config, CommandAction::Queue, "alice", Role::Viewer)
+            .is_ok());
+    }
+
+    #[test]
+    fn exempt_roles_skip_cooldowns() {
+        let config = config(60, 60);
+        let mut cooldowns = Cooldowns::default();
+
+        for _ in 0..3 {
+            assert!(cooldowns
+                .try_use(&config, CommandAction::Skip, "mod", Role::Moderator)
+                .is_ok());
+        }
+
+        // exempt uses are not recorded either
+        assert!(cooldowns
+            .try_use(&config, CommandAction::Skip, "viewer", Role::Viewer)
+            .is_ok());
+        assert!(cooldowns
+            .try_use(&config, CommandAction::Skip, "viewer", Role::Subscriber)
+            .is_err());
+    }
+
+    #[test]
+    fn denied_replies_once_per_user_cooldown() {
+        let config = config(0, 60);
+        let mut cooldowns = Cooldowns::default();
+
+        assert!(cooldowns.try_deny(&config, "alice"));
+        assert!(!cooldowns.try_deny(&config, "Alice"));
+        assert!(cooldowns.try_deny(&config, "bob"));
+
+        // denials do not count as uses
+        assert!(cooldowns
+            .try_use(&config, CommandAction::Skip, "alice", Role::Viewer)
+            .is_ok());
+    }
+
+    #[test]
+    fn zero_cooldowns_allow_every_use() {
+        let config = config(0, 0);
+        let mut cooldowns = Cooldowns::default();
+
+        for _ in 0..3 {
+            assert!(cooldowns
+                .try_use(&config, CommandAction::NowPlaying, "alice", Role::Viewer)
+                .is_ok());
+        }
+    }
+}
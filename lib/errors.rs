@@ -1,5 +1,7 @@
 use core::fmt;
 
+use serde::Serialize;
+
 pub type NPResult<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, Clone)]
@@ -53,6 +55,39 @@ impl std::error::Error for Error {
     }
 }
 
+// one problem with a config value, field is a dotted path like "cooldowns.user_secs"
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, message: String) -> Self {
+        Self {
+            field: field.to_string(),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+impl From<Vec<FieldError>> for Error {
+    fn from(errors: Vec<FieldError>) -> Self {
+        let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+
+        Self {
+            message: errors.join(", "),
+            kind: ErrorKind::ConfigError,
+        }
+    }
+}
+
 impl std::convert::From<hyper::Error> for Error {
     fn from(e: hyper::Error) -> Self {
         Self {
@@ -0,0 +1,127 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::errors::NPResult;
+use crate::track::TrackMetadata;
+use crate::tracker::Tracker;
+use crate::{template, Config};
+
+// files for OBS text and image sources, rewritten when the song changes
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FileOutputConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub text_path: Option<String>,
+    #[serde(default = "default_template")]
+    pub template: String,
+    // written when nothing is playing
+    #[serde(default)]
+    pub nothing_playing_template: String,
+    // the album art is downloaded here, and removed for songs without any
+    #[serde(default)]
+    pub artwork_path: Option<String>,
+}
+
+fn default_template() -> String {
+    String::from("{artist} - {title}")
+}
+
+impl Default for FileOutputConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            text_path: None,
+            template: default_template(),
+            nothing_playing_template: String::new(),
+            artwork_path: None,
+        }
+    }
+}
+
+pub async fn run(config: Arc<Mutex<Config>>, tracker: Arc<Tracker>) {
+    let mut playing = tracker.watch();
+    let http_client = reqwest::Client::new();
+
+    // nothing is known before the first poll, so the files are left as they are until then
+    tracker.first_poll().await;
+
+    loop {
+        let track = playing.borrow_and_update().clone();
+        output(&config, &http_client, track.as_ref()).await;
+
+        if playing.changed().await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn output(
+    config: &Arc<Mutex<Config>>,
+    http_client: &reqwest::Client,
+    track: Option<&TrackMetadata>,
+) {
+    let conf = config.lock().await.file_output.clone();
+    if !conf.enabled {
+        return;
+    }
+
+    if let Some(path) = &conf.text_path {
+        let template = match track {
+            Some(_) => &conf.template,
+            None => &conf.nothing_playing_template,
+        };
+        let written = template::render(template, track, "")
+            .and_then(|text| replace(Path::new(path), text.as_bytes()));
+        if let Err(e) = written {
+            tracing::error!("Failed to write the song to {}: {}", path, e);
+        }
+    }
+
+    if let Some(path) = &conf.artwork_path {
+        if let Err(e) = artwork(http_client, track, Path::new(path)).await {
+            tracing::error!("Failed to write the album art to {}: {}", path, e);
+        }
+    }
+}
+
+async fn artwork(
+    http_client: &reqwest::Client,
+    track: Option<&TrackMetadata>,
+    path: &Path,
+) -> NPResult<()> {
+    let url = match track.and_then(|t| t.artwork_url.as_ref()) {
+        Some(url) => url,
+        // the art of the song before would be wrong, also when nothing plays
+        None => {
+            return match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            }
+        }
+    };
+
+    let image = http_client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+
+    replace(path, &image)
+}
+
+// OBS may read the file at any moment, so it is written next to it and renamed over it
+fn replace(path: &Path, data: &[u8]) -> NPResult<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+
+    std::fs::write(&temp, data)?;
+    std::fs::rename(&temp, path)?;
+
+    Ok(())
+}
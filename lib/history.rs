@@ -0,0 +1,204 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+
+use crate::errors::NPResult;
+use crate::sources::SourceRegistry;
+use crate::track::TrackMetadata;
+use crate::tracker::Tracker;
+use crate::Config;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HistoryConfig {
+    // the oldest songs are dropped past this
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+    // songs listed by !history without a number
+    #[serde(default = "default_count")]
+    pub count: usize,
+    #[serde(default = "default_last_song_template")]
+    pub last_song_template: String,
+}
+
+fn default_max_entries() -> usize {
+    100
+}
+
+fn default_count() -> usize {
+    5
+}
+
+fn default_last_song_template() -> String {
+    String::from("The last song was {artist} - {title} {url}")
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: default_max_entries(),
+            count: default_count(),
+            last_song_template: default_last_song_template(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HistoryEntry {
+    pub track: TrackMetadata,
+    // unix timestamp
+    pub played_at: i64,
+}
+
+// the songs played in one channel, newest last
+#[derive(Debug, Default)]
+pub struct History {
+    entries: Mutex<VecDeque<HistoryEntry>>,
+    // None keeps the history in memory only
+    path: Option<PathBuf>,
+}
+
+impl History {
+    // a missing or unreadable file starts an empty history
+    pub fn open(path: Option<PathBuf>) -> Self {
+        let entries = match &path {
+            Some(path) => match std::fs::read(path) {
+                Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                    tracing::warn!("Ignoring the play history in {}: {}", path.display(), e);
+                    VecDeque::new()
+                }),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => VecDeque::new(),
+                Err(e) => {
+                    tracing::warn!("Could not read {}: {}", path.display(), e);
+                    VecDeque::new()
+                }
+            },
+            None => VecDeque::new(),
+        };
+
+        Self {
+            entries: Mutex::new(entries),
+            path,
+        }
+    }
+
+    pub async fn record(&self, config: &HistoryConfig, track: &TrackMetadata) {
+        let mut entries = self.entries.lock().await;
+
+        if entries.back().is_some_and(|e| e.track.same_track(track)) {
+            return;
+        }
+
+        let mut track = track.clone();
+        track.progress_ms = None;
+        entries.push_back(HistoryEntry {
+            track,
+            played_at: Utc::now().timestamp(),
+        });
+
+        while entries.len() > config.max_entries.max(1) {
+            entries.pop_front();
+        }
+
+        self.save(&entries);
+    }
+
+    // newest first
+    pub async fn recent(&self, count: usize) -> Vec<HistoryEntry> {
+        self.entries
+            .lock()
+            .await
+            .iter()
+            .rev()
+            .take(count)
+            .cloned()
+            .collect()
+    }
+
+    // the song before the one playing now, or the newest one when nothing is playing
+    pub async fn last_song(&self, playing: Option<&TrackMetadata>) -> Option<HistoryEntry> {
+        let entries = self.entries.lock().await;
+        let mut newest = entries.iter().rev();
+
+        match (newest.next(), playing) {
+            (Some(entry), Some(playing)) if entry.track.same_track(playing) => {
+                newest.next().cloned()
+            }
+            (entry, _) => entry.cloned(),
+        }
+    }
+
+    // fills an empty history from a source that remembers what it played
+    pub async fn seed(&self, config: &HistoryConfig, sources: &SourceRegistry) {
+        if !self.entries.lock().await.is_empty() {
+            return;
+        }
+
+        let seeded = match sources.recent_tracks(config.max_entries).await {
+            Some(seeded) => seeded,
+            None => return,
+        };
+
+        let mut entries = self.entries.lock().await;
+        if !entries.is_empty() {
+            return;
+        }
+
+        tracing::info!("Seeded the play history with {} songs", seeded.len());
+        // sources list the newest first
+        entries.extend(seeded.into_iter().rev());
+        self.save(&entries);
+    }
+
+    pub async fn run(
+        self: Arc<Self>,
+        config: Arc<Mutex<Config>>,
+        tracker: Arc<Tracker>,
+        channel: String,
+    ) {
+        let mut changes = tracker.subscribe();
+
+        let conf = config.lock().await.for_channel(&channel).history;
+        self.seed(&conf, tracker.sources()).await;
+
+        loop {
+            match changes.recv().await {
+                Ok(track) => {
+                    let conf = config.lock().await.for_channel(&channel).history;
+                    self.record(&conf, &track).await;
+                }
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    fn save(&self, entries: &VecDeque<HistoryEntry>) {
+        if let Some(path) = &self.path {
+            if let Err(e) = write(path, entries) {
+                tracing::error!("Failed to save the play history: {}", e);
+            }
+        }
+    }
+}
+
+fn write(path: &Path, entries: &VecDeque<HistoryEntry>) -> NPResult<()> {
+    std::fs::write(path, serde_json::to_string(entries)?)?;
+    Ok(())
+}
+
+// how long ago a song was played, for chat
+pub fn ago(played_at: i64) -> String {
+    let secs = (Utc::now().timestamp() - played_at).max(0);
+
+    match secs {
+        0..=59 => String::from("just now"),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::Config;
+
+// later layers win: defaults, then the file, then the token store, then env, then the command line
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigSource {
+    Default,
+    File,
+    TokenStore,
+    Env,
+    Cli,
+    // changed through the web dashboard while running
+    Api,
+    // tokens the bot got while running, from a refresh or the OAuth callback
+    Runtime,
+}
+
+// where each effective value came from, keyed by dotted field path like "cooldowns.user_secs"
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ConfigReport {
+    pub path: String,
+    pub fields: BTreeMap<String, ConfigSource>,
+}
+
+impl ConfigReport {
+    pub fn new(path: &str) -> Self {
+        let mut fields = BTreeMap::new();
+        for key in leaves(&Config::default()).into_keys() {
+            fields.insert(key, ConfigSource::Default);
+        }
+
+        Self {
+            path: path.to_string(),
+            fields,
+        }
+    }
+
+    // every field the file spells out counts as coming from the file, even when it matches the default
+    pub fn record_file(&mut self, raw: &Value, loaded: &Config) {
+        let mut present = BTreeMap::new();
+        flatten(String::new(), raw, &mut present);
+
+        let known = leaves(loaded);
+        for key in present.into_keys() {
+            if known.contains_key(&key) {
+                self.fields.insert(key, ConfigSource::File);
+            }
+        }
+    }
+
+    pub fn record(&mut self, before: &Config, after: &Config, source: ConfigSource) {
+        let before = leaves(before);
+
+        for (key, value) in leaves(after) {
+            if before.get(&key) != Some(&value) {
+                self.fields.insert(key, source);
+            }
+        }
+    }
+}
+
+// every field whose value differs, with its old and new value, removed fields are null
+pub fn changed_fields(before: &Config, after: &Config) -> Vec<(String, Value, Value)> {
+    let before = leaves(before);
+    let after = leaves(after);
+
+    let mut keys: Vec<&String> = before.keys().chain(after.keys()).collect();
+    keys.sort();
+    keys.dedup();
+
+    keys.into_iter()
+        .filter_map(|key| {
+            let old = before.get(key).cloned().unwrap_or(Value::Null);
+            let new = after.get(key).cloned().unwrap_or(Value::Null);
+            (old != new).then_some((key.clone(), old, new))
+        })
+        .collect()
+}
+
+// copies the values that came from source in old over new, so a reloaded file does not undo
+// command line flags
+pub fn keep_source(old: &Config, new: Config, source: ConfigSource) -> Config {
+    let keep: Vec<&String> = old
+        .report
+        .fields
+        .iter()
+        .filter(|(_, s)| **s == source)
+        .map(|(key, _)| key)
+        .collect();
+
+    if keep.is_empty() {
+        return new;
+    }
+
+    let (Ok(old_value), Ok(mut value)) = (serde_json::to_value(old), serde_json::to_value(&new))
+    else {
+        return new;
+    };
+
+    for key in &keep {
+        let pointer = format!("/{}", key.replace('.', "/"));
+        if let (Some(old), Some(slot)) = (old_value.pointer(&pointer), value.pointer_mut(&pointer))
+        {
+            *slot = old.clone();
+        }
+    }
+
+    match serde_json::from_value::<Config>(value) {
+        Ok(mut kept) => {
+            kept.report = new.report;
+            for key in keep {
+                kept.report.fields.insert(key.clone(), source);
+            }
+            kept
+        }
+        Err(_) => new,
+    }
+}
+
+// puts the values that came from one of sources back to what file has, so saving does not write
+// env or command line values into the config file
+pub fn without_sources(config: &Config, file: &Config, sources: &[ConfigSource]) -> Config {
+    let reset: Vec<&String> = config
+        .report
+        .fields
+        .iter()
+        .filter(|(_, s)| sources.contains(s))
+        .map(|(key, _)| key)
+        .collect();
+
+    if reset.is_empty() {
+        return config.clone();
+    }
+
+    let (Ok(file_value), Ok(mut value)) =
+        (serde_json::to_value(file), serde_json::to_value(config))
+    else {
+        return config.clone();
+    };
+
+    for key in reset {
+        let pointer = format!("/{}", key.replace('.', "/"));
+        match file_value.pointer(&pointer) {
+            Some(old) => {
+                if let Some(slot) = value.pointer_mut(&pointer) {
+                    *slot = old.clone();
+                }
+            }
+            // added by the layer, like a channel from CHANNELS
+            None => {
+                let (parent, field) = pointer.rsplit_once('/').unwrap_or_default();
+                if let Some(Value::Object(map)) = value.pointer_mut(parent) {
+                    map.remove(field);
+                }
+            }
+        }
+    }
+
+    match serde_json::from_value::<Config>(value) {
+        Ok(mut restored) => {
+            restored.report = config.report.clone();
+            restored
+        }
+        Err(_) => config.clone(),
+    }
+}
+
+// keeps the values of old for the fields named by keep that new leaves unset, so a reload that
+// finds no tokens does not drop the ones the bot got while running
+pub fn keep_missing(old: &Config, new: Config, keep: impl Fn(&str) -> bool) -> Config {
+    let missing: Vec<(String, Value)> = leaves(old)
+        .into_iter()
+        .filter(|(key, value)| !value.is_null() && keep(key.rsplit('.').next().unwrap_or(key)))
+        .collect();
+
+    let Ok(mut value) = serde_json::to_value(&new) else {
+        return new;
+    };
+
+    let mut kept = false;
+    for (key, old) in missing {
+        let pointer = format!("/{}", key.replace('.', "/"));
+        let (parent, field) = pointer.rsplit_once('/').unwrap_or_default();
+
+        // a removed channel takes its tokens with it
+        if let Some(Value::Object(map)) = value.pointer_mut(parent) {
+            if matches!(map.get(field), None | Some(Value::Null)) {
+                map.insert(field.to_string(), old);
+                kept = true;
+            }
+        }
+    }
+
+    if !kept {
+        return new;
+    }
+
+    match serde_json::from_value::<Config>(value) {
+        Ok(mut restored) => {
+            restored.report = new.report;
+            restored
+        }
+        Err(_) => new,
+    }
+}
+
+fn leaves(config: &Config) -> BTreeMap<String, Value> {
+    let mut out = BTreeMap::new();
+    if let Ok(value) = serde_json::to_value(config) {
+        flatten(String::new(), &value, &mut out);
+    }
+    out
+}
+
+// lists and empty objects are a single value, only other objects are descended into
+fn flatten(prefix: String, value: &Value, out: &mut BTreeMap<String, Value>) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (key, value) in map {
+                let path = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten(path, value, out);
+            }
+        }
+        _ => {
+            out.insert(prefix, value.clone());
+        }
+    }
+}
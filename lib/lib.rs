@@ -1,11 +1,34 @@
+pub mod announcer;
+pub mod channels;
+pub mod commands;
+pub mod config_format;
+pub mod cooldown;
 pub mod errors;
+pub mod file_output;
+pub mod history;
+pub mod layers;
+pub mod migrations;
+pub mod playback;
+pub mod reload;
+pub mod requests;
+pub mod sessions;
 pub mod soundcloud;
+pub mod sources;
 pub mod spotify;
+pub mod template;
+pub mod token_store;
+pub mod track;
+pub mod tracker;
 pub mod twitch;
 
 use colored::Colorize;
+use errors::{Error, ErrorKind, FieldError, NPResult};
 use serde::{Deserialize, Serialize};
-use std::{fs::File, io::Write};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
 
 #[derive(Debug, Default, Clone, PartialEq)]
 pub enum ServerStatus {
@@ -15,8 +38,13 @@ pub enum ServerStatus {
     Stopped,
 }
 
-#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+// fields missing from the file get their value from Config::default()
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct Config {
+    // see migrations::CONFIG_VERSION
+    pub config_version: u32,
+
     pub soundcloud_enabled: bool,
     pub soundcloud_oauth: Option<String>,
 
@@ -28,33 +56,141 @@ pub struct Config {
     pub spotify_oauth_refresh: Option<String>,
 
     pub twitch_client_id: String,
+    // usually kept in the token store instead
     pub twitch_client_secret: String,
     pub twitch_username: String,
 
     pub twitch_oauth: Option<String>,
     pub twitch_oauth_refresh: Option<String>,
+    // unix timestamp, None when unknown
+    pub twitch_oauth_expires_at: Option<i64>,
+
+    // the account that chats, None chats as twitch_username with its tokens
+    pub bot_username: Option<String>,
+    pub bot_oauth: Option<String>,
+    pub bot_oauth_refresh: Option<String>,
+    pub bot_oauth_expires_at: Option<i64>,
+    // the chat joined when channels is empty, None joins twitch_username
+    pub channel: Option<String>,
 
     pub web_dashboard_enabled: bool,
+
+    // music sources are asked in this order, sources not listed are asked last
+    pub source_order: Vec<String>,
+    // when false only the first enabled source is asked
+    pub source_fallback: bool,
+
+    // see template::PLACEHOLDERS, an empty template sends no reply
+    pub now_playing_template: String,
+    pub nothing_playing_template: String,
+
+    pub commands: commands::CommandConfig,
+    pub cooldowns: cooldown::CooldownConfig,
+
+    pub tracker: tracker::TrackerConfig,
+    pub announce: announcer::AnnounceConfig,
+    pub song_requests: requests::SongRequestConfig,
+    pub vote_skip: playback::VoteSkipConfig,
+    pub history: history::HistoryConfig,
+    pub file_output: file_output::FileOutputConfig,
+
+    // keyed by channel login, empty joins only twitch_username
+    pub channels: BTreeMap<String, channels::ChannelConfig>,
+
+    // where secrets and OAuth tokens are kept, they are never written to the config file
+    pub token_store: token_store::TokenStoreConfig,
+
+    #[serde(skip)]
+    pub report: layers::ConfigReport,
+}
+
+// set from the command line, wins over CONFIG_FILE
+static CONFIG_PATH: OnceLock<String> = OnceLock::new();
+
+fn default_source_order() -> Vec<String> {
+    vec![
+        spotify::SOURCE_NAME.to_string(),
+        soundcloud::SOURCE_NAME.to_string(),
+    ]
+}
+
+fn default_source_fallback() -> bool {
+    true
+}
+
+fn default_now_playing_template() -> String {
+    String::from("Now playing: {artist} - {title} {url}")
+}
+
+fn default_nothing_playing_template() -> String {
+    String::from("Nothing is playing right now.")
+}
+
+// also the value of every field a config file leaves out
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            config_version: migrations::CONFIG_VERSION,
+            soundcloud_enabled: false,
+            soundcloud_oauth: None,
+            spotify_enabled: false,
+            spotify_client_id: None,
+            spotify_client_secret: None,
+            spotify_oauth: None,
+            spotify_oauth_refresh: None,
+            twitch_client_id: String::new(),
+            twitch_client_secret: String::new(),
+            twitch_username: String::new(),
+            twitch_oauth: None,
+            twitch_oauth_refresh: None,
+            twitch_oauth_expires_at: None,
+            bot_username: None,
+            bot_oauth: None,
+            bot_oauth_refresh: None,
+            bot_oauth_expires_at: None,
+            channel: None,
+            web_dashboard_enabled: false,
+            source_order: default_source_order(),
+            source_fallback: default_source_fallback(),
+            now_playing_template: default_now_playing_template(),
+            nothing_playing_template: default_nothing_playing_template(),
+            commands: Default::default(),
+            cooldowns: Default::default(),
+            tracker: Default::default(),
+            announce: Default::default(),
+            song_requests: Default::default(),
+            vote_skip: Default::default(),
+            history: Default::default(),
+            file_output: Default::default(),
+            channels: BTreeMap::new(),
+            token_store: Default::default(),
+            report: Default::default(),
+        }
+    }
 }
 
 impl Config {
-    pub fn from_env(conf: Option<Self>) -> Self {
-        // env vars will overwrite anything in json during runtime and save it to json
-        match conf {
+    // non_interactive skips every prompt: env values apply at runtime but are never written to the file
+    pub fn from_env(conf: Option<Self>, non_interactive: bool) -> NPResult<Self> {
+        let mut problems = Vec::new();
+
+        // env vars win over the file while running, they are only saved when the user agrees
+        let c = match conf {
             Some(mut c) => {
+                let before = c.clone();
                 let mut updated = false;
 
                 // Update fields of c only if the environment variable exists
-                if let Ok(value) = std::env::var("SOUNDCLOUD_ENABLED") {
-                    c.soundcloud_enabled = parse_string_to_bool(Some(value));
+                if let Some(value) = env_bool("SOUNDCLOUD_ENABLED", &mut problems) {
+                    c.soundcloud_enabled = value;
                     updated = true;
                 }
                 if let Ok(value) = std::env::var("SOUNDCLOUD_OAUTH") {
                     c.soundcloud_oauth = Some(value);
                     updated = true;
                 }
-                if let Ok(value) = std::env::var("SPOTIFY_ENABLED") {
-                    c.spotify_enabled = parse_string_to_bool(Some(value));
+                if let Some(value) = env_bool("SPOTIFY_ENABLED", &mut problems) {
+                    c.spotify_enabled = value;
                     updated = true;
                 }
 
@@ -83,13 +219,106 @@ impl Config {
                     updated = true;
                 }
 
-                if let Ok(value) = std::env::var("WEB_DASHBOARD_ENABLED") {
-                    c.web_dashboard_enabled = parse_string_to_bool(Some(value));
+                if let Ok(value) = std::env::var("BOT_USERNAME") {
+                    c.bot_username = Some(value);
+                    updated = true;
+                }
+
+                if let Ok(value) = std::env::var("TWITCH_CHANNEL") {
+                    c.channel = Some(value);
+                    updated = true;
+                }
+
+                if let Some(value) = env_bool("WEB_DASHBOARD_ENABLED", &mut problems) {
+                    c.web_dashboard_enabled = value;
+                    updated = true;
+                }
+
+                if let Ok(value) = std::env::var("SOURCE_ORDER") {
+                    c.source_order = parse_string_to_list(&value);
+                    updated = true;
+                }
+
+                if let Some(value) = env_bool("SOURCE_FALLBACK", &mut problems) {
+                    c.source_fallback = value;
+                    updated = true;
+                }
+
+                if let Ok(value) = std::env::var("NOW_PLAYING_TEMPLATE") {
+                    c.now_playing_template = value;
+                    updated = true;
+                }
+
+                if let Ok(value) = std::env::var("NOTHING_PLAYING_TEMPLATE") {
+                    c.nothing_playing_template = value;
+                    updated = true;
+                }
+
+                if let Ok(value) = std::env::var("COMMAND_PREFIX") {
+                    c.commands.prefix = value;
+                    updated = true;
+                }
+
+                if let Some(value) = env_u64("COOLDOWN_GLOBAL", &mut problems) {
+                    c.cooldowns.global_secs = value;
+                    updated = true;
+                }
+
+                if let Some(value) = env_u64("COOLDOWN_USER", &mut problems) {
+                    c.cooldowns.user_secs = value;
+                    updated = true;
+                }
+
+                if let Some(value) = env_bool("ANNOUNCE_ENABLED", &mut problems) {
+                    c.announce.enabled = value;
+                    updated = true;
+                }
+
+                if let Some(value) = env_u64("POLL_INTERVAL", &mut problems) {
+                    c.tracker.poll_interval_secs = value;
+                    updated = true;
+                }
+
+                if let Some(value) = env_u64("TRACK_CACHE_TTL", &mut problems) {
+                    c.tracker.ttl_secs = value;
                     updated = true;
                 }
 
-                if updated {
+                if let Some(value) = env_u64("ANNOUNCE_MIN_GAP", &mut problems) {
+                    c.announce.min_gap_secs = value;
+                    updated = true;
+                }
+
+                if let Some(value) = env_bool("SONG_REQUESTS_ENABLED", &mut problems) {
+                    c.song_requests.enabled = value;
+                    updated = true;
+                }
+
+                if let Ok(value) = std::env::var("CHANNELS") {
+                    let names = parse_string_to_list(&value);
+                    c.channels.retain(|name, _| names.contains(name));
+                    for name in names {
+                        c.channels.entry(name).or_default();
+                    }
+                    updated = true;
+                }
+
+                if let Some(value) = env_token_store("TOKEN_STORE", &mut problems) {
+                    c.token_store.backend = value;
+                    updated = true;
+                }
+
+                if let Ok(value) = std::env::var("TOKEN_STORE_KEY_FILE") {
+                    c.token_store.key_file = Some(value);
+                    updated = true;
+                }
+
+                c.record_changes(&before, layers::ConfigSource::Env);
+
+                if updated && problems.is_empty() && !non_interactive {
                     if yes_no_dialog("You have set environment variables differing from the config. Would you like to overwrite the config file?") {
+                        // saved values belong to the file from now on
+                        c.record_changes(&before, layers::ConfigSource::File);
                         if let Err(_) = c.save_to_file() {
                             tracing::error!("Failed to save config.");
                         }
@@ -99,28 +328,23 @@ impl Config {
                 c
             }
             None => {
-                let spotify_enabled = parse_string_to_bool(std::env::var("SPOTIFY_ENABLED").ok());
+                let spotify_enabled = env_bool("SPOTIFY_ENABLED", &mut problems).unwrap_or(false);
                 let mut spotify_client_id = None;
                 let mut spotify_client_secret = None;
                 let mut spotify_oauth = None;
                 let mut spotify_oauth_refresh = None;
                 if spotify_enabled {
-                    spotify_client_id = Some(
-                        std::env::var("SPOTIFY_CLIENT_ID")
-                            .expect("SPOTIFY_ENABLED is true but SPOTIFY_CLIENT_ID is not set"),
-                    );
-                    spotify_client_secret = Some(
-                        std::env::var("SPOTIFY_CLIENT_SECRET")
-                            .expect("SPOTIFY_ENABLED is true but SPOTIFY_CLIENT_SECRET is not set"),
-                    );
+                    spotify_client_id = Some(env_required("SPOTIFY_CLIENT_ID", &mut problems));
+                    spotify_client_secret =
+                        Some(env_required("SPOTIFY_CLIENT_SECRET", &mut problems));
                     spotify_oauth = std::env::var("SPOTIFY_OAUTH").ok();
                     spotify_oauth_refresh = std::env::var("SPOTIFY_OAUTH_REFRESH").ok();
                 }
 
-                let c = Config {
-                    soundcloud_enabled: parse_string_to_bool(
-                        std::env::var("SOUNDCLOUD_ENABLED").ok(),
-                    ),
+                let mut c = Config {
+                    config_version: migrations::CONFIG_VERSION,
+                    soundcloud_enabled: env_bool("SOUNDCLOUD_ENABLED", &mut problems)
+                        .unwrap_or(false),
                     soundcloud_oauth: std::env::var("SOUNDCLOUD_OAUTH").ok(),
 
                     spotify_enabled,
@@ -130,20 +354,75 @@ impl Config {
                     spotify_oauth,
                     spotify_oauth_refresh,
 
-                    twitch_client_id: std::env::var("TWITCH_CLIENT_ID")
-                        .expect("TWITCH_CLIENT_ID is not set"),
-                    twitch_client_secret: std::env::var("TWITCH_CLIENT_SECRET")
-                        .expect("TWITCH_CLIENT_SECRET is not set"),
-                    twitch_username: std::env::var("TWITCH_USERNAME")
-                        .expect("TWITCH_USERNAME is not set"),
+                    twitch_client_id: env_required("TWITCH_CLIENT_ID", &mut problems),
+                    twitch_client_secret: env_required("TWITCH_CLIENT_SECRET", &mut problems),
+                    twitch_username: env_required("TWITCH_USERNAME", &mut problems),
                     twitch_oauth: std::env::var("TWITCH_OAUTH").ok(),
                     twitch_oauth_refresh: std::env::var("TWITCH_OAUTH_REFRESH").ok(),
-                    web_dashboard_enabled: parse_string_to_bool(
-                        std::env::var("WEB_DASHBOARD_ENABLED").ok(),
-                    ),
+                    twitch_oauth_expires_at: None,
+                    bot_username: std::env::var("BOT_USERNAME").ok(),
+                    bot_oauth: std::env::var("BOT_OAUTH").ok(),
+                    bot_oauth_refresh: std::env::var("BOT_OAUTH_REFRESH").ok(),
+                    bot_oauth_expires_at: None,
+                    channel: std::env::var("TWITCH_CHANNEL").ok(),
+                    web_dashboard_enabled: env_bool("WEB_DASHBOARD_ENABLED", &mut problems)
+                        .unwrap_or(false),
+                    source_order: std::env::var("SOURCE_ORDER")
+                        .map(|v| parse_string_to_list(&v))
+                        .unwrap_or_else(|_| default_source_order()),
+                    source_fallback: env_bool("SOURCE_FALLBACK", &mut problems)
+                        .unwrap_or_else(default_source_fallback),
+                    now_playing_template: std::env::var("NOW_PLAYING_TEMPLATE")
+                        .unwrap_or_else(|_| default_now_playing_template()),
+                    nothing_playing_template: std::env::var("NOTHING_PLAYING_TEMPLATE")
+                        .unwrap_or_else(|_| default_nothing_playing_template()),
+                    commands: commands::CommandConfig {
+                        prefix: std::env::var("COMMAND_PREFIX").unwrap_or_else(|_| "!".into()),
+                        ..Default::default()
+                    },
+                    cooldowns: cooldown::CooldownConfig {
+                        global_secs: env_u64("COOLDOWN_GLOBAL", &mut problems).unwrap_or(5),
+                        user_secs: env_u64("COOLDOWN_USER", &mut problems).unwrap_or(30),
+                        ..Default::default()
+                    },
+                    tracker: tracker::TrackerConfig {
+                        poll_interval_secs: env_u64("POLL_INTERVAL", &mut problems).unwrap_or(10),
+                        ttl_secs: env_u64("TRACK_CACHE_TTL", &mut problems).unwrap_or(30),
+                    },
+                    announce: announcer::AnnounceConfig {
+                        enabled: env_bool("ANNOUNCE_ENABLED", &mut problems).unwrap_or(false),
+                        min_gap_secs: env_u64("ANNOUNCE_MIN_GAP", &mut problems).unwrap_or(60),
+                        ..Default::default()
+                    },
+                    song_requests: requests::SongRequestConfig {
+                        enabled: env_bool("SONG_REQUESTS_ENABLED", &mut problems).unwrap_or(false),
+                        ..Default::default()
+                    },
+                    vote_skip: Default::default(),
+                    history: Default::default(),
+                    file_output: Default::default(),
+                    channels: std::env::var("CHANNELS")
+                        .map(|v| {
+                            parse_string_to_list(&v)
+                                .into_iter()
+                                .map(|name| (name, Default::default()))
+                                .collect()
+                        })
+                        .unwrap_or_default(),
+                    token_store: token_store::TokenStoreConfig {
+                        backend: env_token_store("TOKEN_STORE", &mut problems).unwrap_or_default(),
+                        key_file: std::env::var("TOKEN_STORE_KEY_FILE").ok(),
+                    },
+                    report: layers::ConfigReport::new(&Self::path().unwrap_or_default()),
                 };
 
-                if yes_no_dialog("Would you like to save the config to a file?") {
+                c.record_changes(&Config::default(), layers::ConfigSource::Env);
+
+                if problems.is_empty()
+                    && !non_interactive
+                    && yes_no_dialog("Would you like to save the config to a file?")
+                {
+                    c.record_changes(&Config::default(), layers::ConfigSource::File);
                     if let Err(_) = c.save_to_file() {
                         tracing::error!("Failed to save config.");
                     }
@@ -151,27 +430,330 @@ impl Config {
 
                 c
             }
+        };
+
+        if !problems.is_empty() {
+            return Err(Error::new(
+                format!("Invalid environment. {}", problems.join(", ")),
+                ErrorKind::ConfigError,
+            ));
         }
+
+        Ok(c)
     }
 
-    pub fn default_path() -> String {
-        #[cfg(target_os = "linux")]
-        {
-            let home = std::env::var("HOME").expect("HOME is not set");
-            format!("{}/.config/nowplaying-ttv/config.json", home)
+    // reports every problem at once instead of stopping at the first
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+
+        for (field, value) in [
+            ("twitch_client_id", &self.twitch_client_id),
+            ("twitch_client_secret", &self.twitch_client_secret),
+            ("twitch_username", &self.twitch_username),
+        ] {
+            if value.trim().is_empty() {
+                errors.push(FieldError::new(field, String::from("is required")));
+            }
         }
 
-        #[cfg(target_os = "windows")]
+        for (field, value) in [
+            ("bot_username", &self.bot_username),
+            ("channel", &self.channel),
+        ] {
+            if let Some(value) = value {
+                if !channels::is_valid_name(&value.to_lowercase()) {
+                    errors.push(FieldError::new(
+                        field,
+                        String::from("must be a Twitch login"),
+                    ));
+                }
+            }
+        }
+
+        let shared = self.validate_channel_settings("");
+        let mut channel_errors = Vec::new();
+
+        for name in self.channels.keys() {
+            let prefix = format!("channels.{}.", name);
+
+            if !channels::is_valid_name(name) {
+                channel_errors.push(FieldError::new(
+                    &format!("channels.{}", name),
+                    String::from("must be a Twitch login in lowercase"),
+                ));
+            }
+
+            // problems inherited from the top level are only reported there
+            for error in self.for_channel(name).validate_channel_settings(&prefix) {
+                let inherited = shared.iter().any(|e| {
+                    format!("{}{}", prefix, e.field) == error.field && e.message == error.message
+                });
+                if !inherited {
+                    channel_errors.push(error);
+                }
+            }
+        }
+
+        errors.extend(shared);
+        errors.extend(channel_errors);
+
+        for (field, template) in [
+            ("file_output.template", &self.file_output.template),
+            (
+                "file_output.nothing_playing_template",
+                &self.file_output.nothing_playing_template,
+            ),
+        ] {
+            if let Err(e) = template::validate(template) {
+                errors.push(FieldError::new(field, e.message));
+            }
+        }
+
+        if self.file_output.enabled
+            && self.file_output.text_path.is_none()
+            && self.file_output.artwork_path.is_none()
         {
-            let appdata = std::env::var("APPDATA").expect("APPDATA is not set");
-            format!("{}/nowplaying-ttv/config.json", appdata)
+            errors.push(FieldError::new(
+                "file_output",
+                String::from("needs text_path or artwork_path when enabled"),
+            ));
+        }
+
+        if self.tracker.poll_interval_secs == 0 {
+            errors.push(FieldError::new(
+                "tracker.poll_interval_secs",
+                String::from("must be at least 1"),
+            ));
+        }
+
+        if self.token_store.backend == token_store::TokenStoreBackend::Encrypted {
+            match &self.token_store.key_file {
+                Some(key_file) if !Path::new(key_file).exists() => errors.push(FieldError::new(
+                    "token_store.key_file",
+                    format!("{} does not exist", key_file),
+                )),
+                None if std::env::var("TOKEN_STORE_PASSPHRASE").is_err() => {
+                    errors.push(FieldError::new(
+                        "token_store.key_file",
+                        String::from(
+                            "is required for the encrypted backend unless TOKEN_STORE_PASSPHRASE is set",
+                        ),
+                    ))
+                }
+                _ => {}
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
         }
     }
 
-    pub fn from_json() -> Result<Self, Box<dyn std::error::Error>> {
-        let location = std::env::var("CONFIG_FILE").unwrap_or(Self::default_path());
-        let mut file = match File::open(&location) {
-            Ok(f) => f,
+    // the settings a channel can override, field paths are prefixed with prefix
+    fn validate_channel_settings(&self, prefix: &str) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+
+        let mut require = |field: &str, value: Option<&String>, why: &str| {
+            if !matches!(value, Some(v) if !v.trim().is_empty()) {
+                errors.push(FieldError::new(
+                    &format!("{}{}", prefix, field),
+                    format!("is required{}", why),
+                ));
+            }
+        };
+
+        if self.spotify_enabled {
+            let why = " when spotify_enabled is true";
+            require("spotify_client_id", self.spotify_client_id.as_ref(), why);
+            require(
+                "spotify_client_secret",
+                self.spotify_client_secret.as_ref(),
+                why,
+            );
+        }
+
+        if self.soundcloud_enabled {
+            let why = " when soundcloud_enabled is true";
+            require("soundcloud_oauth", self.soundcloud_oauth.as_ref(), why);
+        }
+
+        for source in &self.source_order {
+            if !source.eq_ignore_ascii_case(spotify::SOURCE_NAME)
+                && !source.eq_ignore_ascii_case(soundcloud::SOURCE_NAME)
+            {
+                errors.push(FieldError::new(
+                    &format!("{}source_order", prefix),
+                    format!("unknown music source \"{}\"", source),
+                ));
+            }
+        }
+
+        for (field, template) in [
+            ("now_playing_template", &self.now_playing_template),
+            ("nothing_playing_template", &self.nothing_playing_template),
+            (
+                "commands.permission_denied_reply",
+                &self.commands.permission_denied_reply,
+            ),
+            ("announce.template", &self.announce.template),
+            (
+                "song_requests.added_template",
+                &self.song_requests.added_template,
+            ),
+            (
+                "history.last_song_template",
+                &self.history.last_song_template,
+            ),
+        ] {
+            if let Err(e) = template::validate(template) {
+                errors.push(FieldError::new(&format!("{}{}", prefix, field), e.message));
+            }
+        }
+
+        for mut error in self.commands.validate() {
+            error.field = format!("{}{}", prefix, error.field);
+            errors.push(error);
+        }
+
+        errors
+    }
+
+    // the channels the bot joins, in lowercase like twitch sends them
+    pub fn channel_names(&self) -> Vec<String> {
+        if self.channels.is_empty() {
+            let channel = self.channel.as_ref().unwrap_or(&self.twitch_username);
+            vec![channel.to_lowercase()]
+        } else {
+            self.channels.keys().cloned().collect()
+        }
+    }
+
+    // the account chat logs in as
+    pub fn chat_username(&self) -> String {
+        self.bot_username
+            .as_ref()
+            .unwrap_or(&self.twitch_username)
+            .to_lowercase()
+    }
+
+    // the tokens of chat_username
+    pub fn chat_tokens(&self) -> twitch::ChatTokens {
+        match self.bot_username {
+            Some(_) => twitch::ChatTokens {
+                oauth: self.bot_oauth.clone(),
+                refresh: self.bot_oauth_refresh.clone(),
+                expires_at: self.bot_oauth_expires_at,
+            },
+            None => twitch::ChatTokens {
+                oauth: self.twitch_oauth.clone(),
+                refresh: self.twitch_oauth_refresh.clone(),
+                expires_at: self.twitch_oauth_expires_at,
+            },
+        }
+    }
+
+    // recorded as runtime values, so they are saved even when the old tokens came from env
+    pub fn set_chat_tokens(&mut self, tokens: twitch::ChatTokens) {
+        self.layer(layers::ConfigSource::Runtime, |c| {
+            let (oauth, refresh, expires_at) = match c.bot_username {
+                Some(_) => (
+                    &mut c.bot_oauth,
+                    &mut c.bot_oauth_refresh,
+                    &mut c.bot_oauth_expires_at,
+                ),
+                None => (
+                    &mut c.twitch_oauth,
+                    &mut c.twitch_oauth_refresh,
+                    &mut c.twitch_oauth_expires_at,
+                ),
+            };
+
+            *oauth = tokens.oauth;
+            *refresh = tokens.refresh;
+            *expires_at = tokens.expires_at;
+        });
+    }
+
+    // the top level settings with the overrides of one channel applied
+    pub fn for_channel(&self, channel: &str) -> Config {
+        let mut conf = self.clone();
+        if let Some(overrides) = self.channels.get(channel) {
+            overrides.apply(&mut conf);
+        }
+        conf
+    }
+
+    pub fn source_priority(&self, name: &str) -> usize {
+        self.source_order
+            .iter()
+            .position(|s| s.eq_ignore_ascii_case(name))
+            .unwrap_or(self.source_order.len())
+    }
+
+    pub fn default_path() -> NPResult<String> {
+        Ok(format!("{}/nowplaying-ttv/config.json", config_dir()?))
+    }
+
+    // the command line wins over CONFIG_FILE, which wins over the default path
+    pub fn path() -> NPResult<String> {
+        if let Some(path) = CONFIG_PATH.get() {
+            return Ok(path.clone());
+        }
+
+        match std::env::var("CONFIG_FILE") {
+            Ok(path) => Ok(path),
+            Err(_) => Self::default_path(),
+        }
+    }
+
+    // the bot keeps its other files, like tokens, history and sessions, next to the config file
+    pub fn dir() -> NPResult<PathBuf> {
+        Ok(Path::new(&Self::path()?)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default())
+    }
+
+    // only the first call has an effect
+    pub fn set_path(path: String) {
+        let _ = CONFIG_PATH.set(path);
+    }
+
+    // applies a change and remembers it as coming from source
+    pub fn layer(&mut self, source: layers::ConfigSource, f: impl FnOnce(&mut Self)) {
+        let before = self.clone();
+        f(self);
+        self.record_changes(&before, source);
+    }
+
+    // marks every value that differs from before as coming from source
+    pub fn record_changes(&mut self, before: &Config, source: layers::ConfigSource) {
+        let mut report = std::mem::take(&mut self.report);
+        report.record(before, self, source);
+        self.report = report;
+    }
+
+    pub fn token_store(&self) -> NPResult<Box<dyn token_store::TokenStore>> {
+        Ok(token_store::open(&self.token_store, &Self::dir()?))
+    }
+
+    // fills in the secrets the config file does not have from the token store
+    pub fn load_secrets(&mut self) -> NPResult<()> {
+        let secrets = self.token_store()?.load()?;
+        self.layer(layers::ConfigSource::TokenStore, |c| secrets.merge_into(c));
+
+        Ok(())
+    }
+
+    // the format follows the extension, see config_format::ConfigFormat
+    pub fn from_file() -> Result<Self, Box<dyn std::error::Error>> {
+        let location = Self::path()?;
+        let format = config_format::ConfigFormat::from_path(&location)?;
+
+        let text = match std::fs::read_to_string(&location) {
+            Ok(t) => t,
             Err(e) => {
                 tracing::error!(
                     "A non fatal error occured while opening {}. {}",
@@ -183,81 +765,259 @@ impl Config {
                 );
             }
         };
-        let data: Self = match serde_json::from_reader(&mut file) {
+        let mut raw = match format.parse(&text) {
+            Ok(raw) => raw,
+            Err(e) => {
+                tracing::error!(
+                    "A non fatal error occured while deserializing the file. (The file is invalid) {}",
+                    e
+                );
+                return Err(format!(
+                    "A non fatal error occured while deserializing the file. (The file is invalid) {}",
+                    e
+                )
+                .into());
+            }
+        };
+        let migrated_from = migrations::migrate(&mut raw)?;
+
+        let mut data: Self = match serde_json::from_value(raw.clone()) {
             Ok(d) => d,
             Err(e) => {
                 tracing::error!(
-                    "A non fatal error occured while deserializing the file. (The JSON doesn't match) {}",
+                    "A non fatal error occured while deserializing the file. (The fields don't match) {}",
                     e
                 );
                 return Err(format!(
-                    "A non fatal error occured while deserializing the file. (The JSON doesn't match) {}",
+                    "A non fatal error occured while deserializing the file. (The fields don't match) {}",
                     e
                 )
                 .into());
             }
         };
 
+        // only written back once the upgraded file is known to load
+        if let Some(from) = migrated_from {
+            let migrated = format.render(&raw, Some(&text))?;
+
+            let backup = format!("{}.v{}.bak", location, from);
+            std::fs::copy(&location, &backup)?;
+            reload::wrote(migrated.as_bytes());
+            token_store::write_private(Path::new(&location), migrated.as_bytes())?;
+
+            tracing::info!(
+                "Upgraded {} from version {} to {}, the old file is kept as {}",
+                location,
+                from,
+                migrations::CONFIG_VERSION,
+                backup
+            );
+        }
+
+        let mut report = layers::ConfigReport::new(&location);
+        report.record_file(&raw, &data);
+        data.report = report;
+
         Ok(data)
     }
 
     pub fn save_to_file(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let location = std::env::var("CONFIG_FILE").unwrap_or(Self::default_path());
+        let location = Self::path()?;
 
-        let conf_json = match serde_json::to_string_pretty(&self) {
-            Ok(s) => s,
-            Err(e) => {
+        if let Some(dir) = Path::new(&location).parent() {
+            if let Err(e) = std::fs::create_dir_all(dir) {
                 tracing::error!(
-                    "A non fatal error occured while serializing the struct into a string. {}",
+                    "A non fatal error occured while creating {}. {}",
+                    dir.display(),
                     e
                 );
                 return Err(format!(
-                    "A non fatal error occured while serializing the struct into a string. {}",
+                    "A non fatal error occured while creating {}. {}",
+                    dir.display(),
                     e
                 )
                 .into());
             }
-        };
+        }
 
-        match File::create(location.clone()) {
-            Ok(mut f) => {
-                if let Err(e) = f.write_all(conf_json.as_bytes()) {
-                    tracing::error!(
-                        "A non fatal error occured while writing the string into the file. {}",
-                        e
-                    );
-                    return Err(format!(
-                        "A non fatal error occured while writing the string into the file. {}",
-                        e
-                    )
-                    .into());
-                }
-            }
+        let format = config_format::ConfigFormat::from_path(&location)?;
+        let existing = std::fs::read_to_string(&location).ok();
+
+        // only the file and token store layers are written back, env and command line values stay
+        // where they came from
+        let mut saved: Config = existing
+            .as_deref()
+            .and_then(|text| format.parse(text).ok())
+            .and_then(|raw| serde_json::from_value(raw).ok())
+            .unwrap_or_default();
+        let store = self.token_store()?;
+        match store.load() {
+            Ok(stored) => stored.merge_into(&mut saved),
             Err(e) => {
-                tracing::error!("A non fatal error occured while creating the file. {}", e);
+                tracing::error!("A non fatal error occured while reading the tokens. {}", e);
                 return Err(
-                    format!("A non fatal error occured while creating the file. {}", e).into(),
+                    format!("A non fatal error occured while reading the tokens. {}", e).into(),
                 );
             }
+        }
+
+        let mut public = layers::without_sources(
+            self,
+            &saved,
+            &[layers::ConfigSource::Env, layers::ConfigSource::Cli],
+        );
+        let secrets = token_store::Secrets::take(&mut public);
+
+        if let Err(e) = store.save(&secrets) {
+            tracing::error!("A non fatal error occured while saving the tokens. {}", e);
+            return Err(format!("A non fatal error occured while saving the tokens. {}", e).into());
+        }
+
+        let conf_text = match format.render(&public, existing.as_deref()) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!(
+                    "A non fatal error occured while serializing the struct into a string. {}",
+                    e
+                );
+                return Err(format!(
+                    "A non fatal error occured while serializing the struct into a string. {}",
+                    e
+                )
+                .into());
+            }
         };
 
+        reload::wrote(conf_text.as_bytes());
+        if let Err(e) = token_store::write_private(Path::new(&location), conf_text.as_bytes()) {
+            tracing::error!(
+                "A non fatal error occured while writing the string into the file. {}",
+                e
+            );
+            return Err(format!(
+                "A non fatal error occured while writing the string into the file. {}",
+                e
+            )
+            .into());
+        }
+
         tracing::info!("Saved config to {}", location);
 
         Ok(())
     }
+
+    // saves a snapshot off the async threads, the token store may take a while to derive its key,
+    // so callers clone the config and release its lock first
+    pub async fn save(self) -> NPResult<()> {
+        tokio::task::spawn_blocking(move || {
+            self.save_to_file()
+                .map_err(|e| Error::new(e.to_string(), ErrorKind::FileError))
+        })
+        .await
+        .map_err(|e| Error::new(e.to_string(), ErrorKind::UnknownError))?
+    }
 }
 
-fn parse_string_to_bool(s: Option<String>) -> bool {
-    match s {
-        Some(s) => match s.trim().to_lowercase().as_str() {
-            "true" | "1" | "yes" | "y" | "on" => true,
-            "false" | "0" | "no" | "n" | "off" => false,
-            _ => true,
-        },
-        None => false,
+// where the nowplaying-ttv directory goes, following each platform's convention
+fn config_dir() -> NPResult<String> {
+    let env = |key: &str| std::env::var(key).ok().filter(|v| !v.is_empty());
+
+    #[cfg(target_os = "windows")]
+    let dir = env("APPDATA");
+
+    // XDG_CONFIG_HOME is honoured everywhere else, the spec says to ignore relative paths
+    #[cfg(not(target_os = "windows"))]
+    let dir = env("XDG_CONFIG_HOME").filter(|d| Path::new(d).is_absolute());
+
+    #[cfg(target_os = "macos")]
+    let dir =
+        dir.or_else(|| env("HOME").map(|home| format!("{}/Library/Application Support", home)));
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    let dir = dir.or_else(|| env("HOME").map(|home| format!("{}/.config", home)));
+
+    dir.ok_or_else(|| {
+        Error::new(
+            String::from(
+                "Could not find a config directory, set CONFIG_FILE or pass --config with the path to the config file",
+            ),
+            ErrorKind::ConfigError,
+        )
+    })
+}
+
+fn parse_bool(s: &str) -> Option<bool> {
+    match s.trim().to_lowercase().as_str() {
+        "true" | "1" | "yes" | "y" | "on" => Some(true),
+        "false" | "0" | "no" | "n" | "off" => Some(false),
+        _ => None,
     }
 }
 
+// the env_* helpers return None when the variable is unset, and also record why when it is invalid
+fn env_required(key: &str, problems: &mut Vec<String>) -> String {
+    match std::env::var(key) {
+        Ok(value) => value,
+        Err(_) => {
+            problems.push(format!("{} is not set", key));
+            String::new()
+        }
+    }
+}
+
+fn env_bool(key: &str, problems: &mut Vec<String>) -> Option<bool> {
+    let value = std::env::var(key).ok()?;
+    let parsed = parse_bool(&value);
+    if parsed.is_none() {
+        problems.push(format!("{} is not true or false ({})", key, value));
+    }
+    parsed
+}
+
+fn env_u64(key: &str, problems: &mut Vec<String>) -> Option<u64> {
+    let value = std::env::var(key).ok()?;
+    match value.trim().parse() {
+        Ok(v) => Some(v),
+        Err(_) => {
+            problems.push(format!("{} is not a whole number ({})", key, value));
+            None
+        }
+    }
+}
+
+fn env_token_store(
+    key: &str,
+    problems: &mut Vec<String>,
+) -> Option<token_store::TokenStoreBackend> {
+    let value = std::env::var(key).ok()?;
+    match value.trim().to_lowercase().as_str() {
+        "plain" => Some(token_store::TokenStoreBackend::Plain),
+        "encrypted" => Some(token_store::TokenStoreBackend::Encrypted),
+        "env" => Some(token_store::TokenStoreBackend::Env),
+        _ => {
+            problems.push(format!(
+                "{} is not plain, encrypted or env ({})",
+                key, value
+            ));
+            None
+        }
+    }
+}
+
+pub fn non_interactive_from_env() -> bool {
+    std::env::var("NOWPLAYING_NONINTERACTIVE")
+        .ok()
+        .and_then(|v| parse_bool(&v))
+        .unwrap_or(false)
+}
+
+fn parse_string_to_list(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(|v| v.trim().to_lowercase())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
 fn yes_no_dialog(query: &str) -> bool {
     println!("{} [{}/{}]: ", query, "Y".green(), "n".red());
 
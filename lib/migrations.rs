@@ -0,0 +1,285 @@
+use serde_json::Value;
+
+use crate::commands::{CommandAction, CommandConfig};
+use crate::errors::{Error, ErrorKind, NPResult};
+
+pub const CONFIG_VERSION: u32 = 5;
+
+// MIGRATIONS[i] upgrades a config from version i + 1 to version i + 2
+const MIGRATIONS: &[fn(&mut serde_json::Map<String, Value>)] =
+    &[v1_to_v2, v2_to_v3, v3_to_v4, v4_to_v5];
+
+// files from before config_version existed are version 1
+pub fn version(raw: &Value) -> NPResult<u32> {
+    let version = match raw.get("config_version") {
+        Some(version) => version,
+        None => return Ok(1),
+    };
+
+    version
+        .as_u64()
+        .and_then(|v| u32::try_from(v).ok())
+        .ok_or_else(|| {
+            Error::new(
+                format!("The config has an invalid config_version {}", version),
+                ErrorKind::ConfigError,
+            )
+        })
+}
+
+// returns the version the config was upgraded from, None when it was already current
+pub fn migrate(raw: &mut Value) -> NPResult<Option<u32>> {
+    let from = version(raw)?;
+
+    if from > CONFIG_VERSION {
+        return Err(Error::new(
+            format!(
+                "The config is version {}, this version of nowplaying-ttv only knows up to {}",
+                from, CONFIG_VERSION
+            ),
+            ErrorKind::ConfigError,
+        ));
+    }
+
+    if from < 1 {
+        return Err(Error::new(
+            format!("The config has an invalid config_version {}", from),
+            ErrorKind::ConfigError,
+        ));
+    }
+
+    if from == CONFIG_VERSION {
+        return Ok(None);
+    }
+
+    let map = raw.as_object_mut().ok_or_else(|| {
+        Error::new(
+            String::from("The config is not a table of settings"),
+            ErrorKind::ConfigError,
+        )
+    })?;
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(from as usize - 1) {
+        migration(map);
+        map.insert(String::from("config_version"), Value::from(i as u32 + 2));
+        tracing::info!("Migrated config to version {}", i + 2);
+    }
+
+    Ok(Some(from))
+}
+
+// version 1 spelled every field out, settings added since then are filled in with their defaults
+// when loading, and unset optional values were written as null
+fn v1_to_v2(map: &mut serde_json::Map<String, Value>) {
+    map.retain(|_, v| !v.is_null());
+}
+
+// version 3 added the song request commands
+fn v2_to_v3(map: &mut serde_json::Map<String, Value>) {
+    add_default_commands(map, &[CommandAction::SongRequest, CommandAction::Queue]);
+}
+
+// version 4 added the playback control and vote skip commands
+fn v3_to_v4(map: &mut serde_json::Map<String, Value>) {
+    add_default_commands(
+        map,
+        &[
+            CommandAction::Skip,
+            CommandAction::Pause,
+            CommandAction::Resume,
+            CommandAction::Volume,
+            CommandAction::VoteSkip,
+        ],
+    );
+}
+
+// version 5 added the play history commands
+fn v4_to_v5(map: &mut serde_json::Map<String, Value>) {
+    add_default_commands(map, &[CommandAction::LastSong, CommandAction::History]);
+}
+
+// configs that list their own commands get the default ones for actions added since, the others
+// get every default command when loading anyway
+fn add_default_commands(map: &mut serde_json::Map<String, Value>, actions: &[CommandAction]) {
+    add_to_list(map.get_mut("commands"), actions);
+
+    if let Some(channels) = map.get_mut("channels").and_then(Value::as_object_mut) {
+        for channel in channels.values_mut() {
+            add_to_list(channel.get_mut("commands"), actions);
+        }
+    }
+}
+
+fn add_to_list(commands: Option<&mut Value>, actions: &[CommandAction]) {
+    let list = match commands
+        .and_then(|c| c.get_mut("commands"))
+        .and_then(Value::as_array_mut)
+    {
+        Some(list) => list,
+        None => return,
+    };
+
+    let defaults = CommandConfig::default()
+        .commands
+        .into_iter()
+        .filter(|c| actions.contains(&c.action))
+        .filter_map(|c| serde_json::to_value(c).ok());
+
+    for default in defaults {
+        let action = &default["action"];
+        if list.iter().any(|c| &c["action"] == action) {
+            continue;
+        }
+
+        // a trigger the config already uses for something else would make it invalid
+        if triggers(&default).any(|t| list.iter().any(|c| triggers(c).any(|u| u == t))) {
+            tracing::warn!(
+                "Not adding the {} command, its trigger is already in use",
+                action
+            );
+            continue;
+        }
+
+        list.push(default);
+    }
+}
+
+fn triggers(command: &Value) -> impl Iterator<Item = String> + '_ {
+    let aliases = command
+        .get("aliases")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten();
+
+    std::iter::once(&command["trigger"])
+        .chain(aliases)
+        .filter_map(Value::as_str)
+        .map(str::to_lowercase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn migrates_v1_to_v2() {
+        let mut raw = json!({ "twitch_username": "streamer", "spotify_oauth": null });
+
+        assert_eq!(migrate(&mut raw).unwrap(), Some(1));
+        assert_eq!(version(&raw).unwrap(), CONFIG_VERSION);
+        assert!(raw.get("spotify_oauth").is_none());
+        assert_eq!(raw["twitch_username"], "streamer");
+    }
+
+    fn actions(commands: &Value) -> Vec<&str> {
+        commands["commands"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["action"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn adds_song_request_commands_in_v2_to_v3() {
+        let mut raw = json!({
+            "commands": { "commands": [
+                { "action": "now_playing", "trigger": "song" },
+                { "action": "queue", "trigger": "q" }
+            ] },
+            "channels": {
+                "other": { "commands": { "commands": [{ "action": "now_playing", "trigger": "sr" }] } },
+                "plain": { "announce": { "enabled": true } }
+            }
+        });
+
+        v2_to_v3(raw.as_object_mut().unwrap());
+
+        // queue is already there, and only the commands of version 3 are added
+        assert_eq!(
+            actions(&raw["commands"]),
+            ["now_playing", "queue", "song_request"]
+        );
+        // sr is taken by another command
+        assert_eq!(
+            actions(&raw["channels"]["other"]["commands"]),
+            ["now_playing", "queue"]
+        );
+        assert!(raw["channels"]["plain"].get("commands").is_none());
+    }
+
+    #[test]
+    fn adds_playback_commands_in_v3_to_v4() {
+        let mut raw = json!({
+            "commands": { "commands": [{ "action": "now_playing", "trigger": "np" }] },
+            "channels": {
+                "other": { "commands": { "commands": [{ "action": "skip", "trigger": "next" }] } }
+            }
+        });
+
+        v3_to_v4(raw.as_object_mut().unwrap());
+
+        assert_eq!(
+            actions(&raw["commands"]),
+            [
+                "now_playing",
+                "skip",
+                "pause",
+                "resume",
+                "volume",
+                "vote_skip"
+            ]
+        );
+        assert_eq!(
+            actions(&raw["channels"]["other"]["commands"]),
+            ["skip", "pause", "resume", "volume", "vote_skip"]
+        );
+        // the added playback controls keep their moderator permission
+        assert_eq!(raw["commands"]["commands"][1]["permission"], "moderator");
+        assert_eq!(raw["commands"]["commands"][5]["permission"], "viewer");
+    }
+
+    #[test]
+    fn adds_history_commands_in_v4_to_v5() {
+        let mut raw = json!({
+            "commands": { "commands": [{ "action": "history", "trigger": "played" }] }
+        });
+
+        v4_to_v5(raw.as_object_mut().unwrap());
+
+        assert_eq!(actions(&raw["commands"]), ["history", "last_song"]);
+    }
+
+    #[test]
+    fn leaves_current_config_alone() {
+        let mut raw = json!({ "config_version": CONFIG_VERSION, "spotify_oauth": null });
+        let before = raw.clone();
+
+        assert_eq!(migrate(&mut raw).unwrap(), None);
+        assert_eq!(raw, before);
+    }
+
+    #[test]
+    fn rejects_newer_config() {
+        let mut raw = json!({ "config_version": CONFIG_VERSION + 1 });
+
+        assert!(migrate(&mut raw).is_err());
+    }
+
+    #[test]
+    fn rejects_version_zero() {
+        let mut raw = json!({ "config_version": 0 });
+
+        assert!(migrate(&mut raw).is_err());
+    }
+
+    #[test]
+    fn rejects_versions_that_are_not_a_u32() {
+        for version in [json!(4_294_967_298u64), json!(-1), json!("2"), json!(2.5)] {
+            let mut raw = json!({ "config_version": version });
+
+            assert!(migrate(&mut raw).is_err(), "{}", version);
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::track::TrackMetadata;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Playback {
+    Skip,
+    Pause,
+    Resume,
+    // percent, 0 to 100
+    Volume(u8),
+}
+
+// sent in chat once the source has done it
+impl fmt::Display for Playback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Playback::Skip => write!(f, "Skipped the song."),
+            Playback::Pause => write!(f, "Paused playback."),
+            Playback::Resume => write!(f, "Resumed playback."),
+            Playback::Volume(percent) => write!(f, "Volume set to {}%.", percent),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VoteSkipConfig {
+    // votes needed to skip the current song
+    #[serde(default = "default_threshold")]
+    pub threshold: usize,
+    // votes older than this no longer count
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+}
+
+fn default_threshold() -> usize {
+    3
+}
+
+fn default_window_secs() -> u64 {
+    60
+}
+
+impl Default for VoteSkipConfig {
+    fn default() -> Self {
+        Self {
+            threshold: default_threshold(),
+            window_secs: default_window_secs(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Vote {
+    Counted { votes: usize, needed: usize },
+    AlreadyVoted { votes: usize, needed: usize },
+    Passed,
+}
+
+// the votes to skip the song playing in one channel
+#[derive(Debug, Default)]
+pub struct VoteSkip {
+    voters: HashSet<String>,
+    started_at: Option<Instant>,
+    track_url: Option<String>,
+}
+
+impl VoteSkip {
+    // a new song or an expired window starts the count over
+    pub fn vote(
+        &mut self,
+        config: &VoteSkipConfig,
+        user: &str,
+        playing: Option<&TrackMetadata>,
+    ) -> Vote {
+        let window = Duration::from_secs(config.window_secs);
+        let track_url = playing.map(|t| t.url.clone());
+
+        let expired = !matches!(self.started_at, Some(at) if at.elapsed() < window);
+        if expired || self.track_url != track_url {
+            self.reset();
+            self.started_at = Some(Instant::now());
+            self.track_url = track_url;
+        }
+
+        let needed = config.threshold.max(1);
+        let counted = self.voters.insert(user.to_lowercase());
+        let votes = self.voters.len();
+
+        // the votes are kept until the skip worked, so a failed skip can be voted again
+        if votes >= needed {
+            return Vote::Passed;
+        }
+
+        if counted {
+            Vote::Counted { votes, needed }
+        } else {
+            Vote::AlreadyVoted { votes, needed }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(url: &str) -> TrackMetadata {
+        TrackMetadata {
+            url: url.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn config(threshold: usize, window_secs: u64) -> VoteSkipConfig {
+        VoteSkipConfig {
+            threshold,
+            window_secs,
+        }
+    }
+
+    #[test]
+    fn passes_at_the_threshold() {
+        let config = config(3, 60);
+        let song = track("a");
+        let mut votes = VoteSkip::default();
+
+        assert_eq!(
+            votes.vote(&config, "alice", Some(&song)),
+            Vote::Counted {
+                votes: 1,
+                needed: 3
+            }
+        );
+        assert_eq!(
+            votes.vote(&config, "bob", Some(&song)),
+            Vote::Counted {
+                votes: 2,
+                needed: 3
+            }
+        );
+        assert_eq!(votes.vote(&config, "carol", Some(&song)), Vote::Passed);
+
+        // until the skip resets them, more votes pass again
+        assert_eq!(votes.vote(&config, "dave", Some(&song)), Vote::Passed);
+
+        votes.reset();
+        assert_eq!(
+            votes.vote(&config, "alice", Some(&song)),
+            Vote::Counted {
+                votes: 1,
+                needed: 3
+            }
+        );
+    }
+
+    #[test]
+    fn counts_each_user_once() {
+        let config = config(3, 60);
+        let song = track("a");
+        let mut votes = VoteSkip::default();
+
+        votes.vote(&config, "alice", Some(&song));
+        assert_eq!(
+            votes.vote(&config, "Alice", Some(&song)),
+            Vote::AlreadyVoted {
+                votes: 1,
+                needed: 3
+            }
+        );
+    }
+
+    #[test]
+    fn new_song_starts_over() {
+        let config = config(2, 60);
+        let mut votes = VoteSkip::default();
+
+        votes.vote(&config, "alice", Some(&track("a")));
+        assert_eq!(
+            votes.vote(&config, "bob", Some(&track("b"))),
+            Vote::Counted {
+                votes: 1,
+                needed: 2
+            }
+        );
+    }
+
+    #[test]
+    fn expired_window_starts_over() {
+        let config = config(2, 0);
+        let song = track("a");
+        let mut votes = VoteSkip::default();
+
+        votes.vote(&config, "alice", Some(&song));
+        assert_eq!(
+            votes.vote(&config, "bob", Some(&song)),
+            Vote::Counted {
+                votes: 1,
+                needed: 2
+            }
+        );
+    }
+
+    #[test]
+    fn threshold_of_zero_needs_one_vote() {
+        let mut votes = VoteSkip::default();
+
+        assert_eq!(
+            votes.vote(&config(0, 60), "alice", Some(&track("a"))),
+            Vote::Passed
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::{broadcast, Mutex};
+
+use crate::errors::{Error, ErrorKind, NPResult};
+use crate::layers::{self, ConfigSource};
+use crate::soundcloud::{self, SoundcloudClient};
+use crate::sources::SourceRegistry;
+use crate::spotify::{self, SpotifyClient};
+use crate::token_store::SECRET_FIELDS;
+use crate::Config;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// what the bot last wrote to the config file itself, its own writes are not reloaded
+static WRITTEN: std::sync::Mutex<Option<u64>> = std::sync::Mutex::new(None);
+
+// kept from the running config like the secrets when a reload leaves them unset
+const RUNTIME_FIELDS: &[&str] = &["twitch_oauth_expires_at", "bot_oauth_expires_at"];
+
+const SOUNDCLOUD_FIELDS: &[&str] = &["soundcloud_enabled", "soundcloud_oauth", "source_order"];
+// the tokens are left out, the running client refreshes them itself
+const SPOTIFY_FIELDS: &[&str] = &[
+    "spotify_enabled",
+    "spotify_client_id",
+    "spotify_client_secret",
+    "source_order",
+];
+
+// the single place the running config is replaced, so running clients hear about every change
+#[derive(Debug)]
+pub struct Reloader {
+    config: Arc<Mutex<Config>>,
+    changes: broadcast::Sender<Arc<Vec<String>>>,
+}
+
+impl Reloader {
+    pub fn new(config: Arc<Mutex<Config>>) -> Self {
+        let (changes, _) = broadcast::channel(16);
+
+        Self { config, changes }
+    }
+
+    // receives the paths of the fields that changed, see layers::ConfigReport
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Vec<String>>> {
+        self.changes.subscribe()
+    }
+
+    pub async fn apply(&self, new: Config, source: ConfigSource) {
+        let mut config = self.config.lock().await;
+
+        let new = layers::keep_source(&config, new, ConfigSource::Cli);
+        // tokens from the OAuth flow may only live in memory, for example with the env token store
+        let mut new = layers::keep_missing(&config, new, |field| {
+            SECRET_FIELDS.contains(&field) || RUNTIME_FIELDS.contains(&field)
+        });
+        let changed = layers::changed_fields(&config, &new);
+
+        if changed.is_empty() {
+            return;
+        }
+
+        for (field, old, value) in &changed {
+            // per channel secrets end in the same names as the top level ones
+            let name = field.rsplit('.').next().unwrap_or(field);
+            if SECRET_FIELDS.contains(&name) {
+                tracing::info!("Config changed: {} (hidden)", field);
+            } else {
+                tracing::info!("Config changed: {} {} -> {}", field, old, value);
+            }
+        }
+
+        new.report = std::mem::take(&mut config.report);
+        new.record_changes(&config, source);
+        *config = new;
+        drop(config);
+
+        let fields = changed.into_iter().map(|(field, _, _)| field).collect();
+        // nobody listening is fine, the next listener reads the new config when it starts
+        let _ = self.changes.send(Arc::new(fields));
+    }
+
+    pub async fn watch(self: Arc<Self>) {
+        let path = match Config::path() {
+            Ok(path) => path,
+            Err(e) => {
+                tracing::error!("Not watching the config for changes. {}", e);
+                return;
+            }
+        };
+        let mut last_modified = modified(&path);
+        let mut last_contents = contents_hash(&path);
+
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+
+            let modified = modified(&path);
+            if modified == last_modified {
+                continue;
+            }
+            last_modified = modified;
+
+            let contents = contents_hash(&path);
+            if contents == last_contents {
+                continue;
+            }
+            last_contents = contents;
+
+            if contents.is_some() && contents == *WRITTEN.lock().unwrap() {
+                tracing::debug!("Not reloading {}, the bot saved it", path);
+                continue;
+            }
+
+            // the encrypted token store derives its key on the first load
+            let loaded = tokio::task::spawn_blocking(load)
+                .await
+                .unwrap_or_else(|e| Err(Error::new(e.to_string(), ErrorKind::UnknownError)));
+
+            match loaded {
+                Ok(config) => self.apply(config, ConfigSource::File).await,
+                Err(e) => tracing::error!("Not reloading {}. {}", path, e),
+            }
+        }
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn hash(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn contents_hash(path: &str) -> Option<u64> {
+    std::fs::read(path).ok().map(|data| hash(&data))
+}
+
+// called with everything the bot writes to the config file
+pub fn wrote(data: &[u8]) {
+    *WRITTEN.lock().unwrap() = Some(hash(data));
+}
+
+// the same layers as on startup, without prompting
+fn load() -> NPResult<Config> {
+    let mut config =
+        Config::from_file().map_err(|e| Error::new(e.to_string(), ErrorKind::ConfigError))?;
+    config.load_secrets()?;
+
+    let config = Config::from_env(Some(config), true)?;
+    config.validate()?;
+
+    Ok(config)
+}
+
+// whether changed holds a setting the music sources are built from
+pub fn touches_sources(changed: &[String]) -> bool {
+    changed.iter().any(|c| {
+        SOUNDCLOUD_FIELDS.contains(&c.as_str())
+            || SPOTIFY_FIELDS.contains(&c.as_str())
+            || c == "source_fallback"
+    })
+}
+
+// rebuilds only the music sources whose settings are in changed
+pub fn apply_to_sources(
+    config: &Arc<Mutex<Config>>,
+    conf: &Config,
+    sources: &SourceRegistry,
+    changed: &[String],
+) {
+    let touches = |fields: &[&str]| changed.iter().any(|c| fields.contains(&c.as_str()));
+
+    if touches(SOUNDCLOUD_FIELDS) {
+        let client = SoundcloudClient::from_config(conf);
+        sources.replace(
+            soundcloud::SOURCE_NAME,
+            client.map(|c| Arc::new(c) as Arc<_>),
+        );
+    }
+
+    if touches(SPOTIFY_FIELDS) {
+        let client = SpotifyClient::from_config(config.clone(), conf, None, None);
+        sources.replace(spotify::SOURCE_NAME, client.map(|c| Arc::new(c) as Arc<_>));
+    }
+
+    if touches(&["source_fallback"]) {
+        sources.set_fallback(conf.source_fallback);
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+
+use crate::commands::Role;
+use crate::errors::Error;
+use crate::sources::SourceRegistry;
+use crate::template;
+use crate::track::TrackMetadata;
+use crate::tracker::Tracker;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SongRequestConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // 0 allows songs of any length
+    #[serde(default = "default_max_duration_secs")]
+    pub max_duration_secs: u64,
+    #[serde(default = "default_allow_explicit")]
+    pub allow_explicit: bool,
+    // requests by one user still waiting to be played, moderators and the broadcaster have no limit
+    #[serde(default = "default_max_per_user")]
+    pub max_per_user: usize,
+    // track links or ids
+    #[serde(default)]
+    pub blocked_tracks: Vec<String>,
+    // compared without case
+    #[serde(default)]
+    pub blocked_artists: Vec<String>,
+    #[serde(default = "default_added_template")]
+    pub added_template: String,
+}
+
+fn default_max_duration_secs() -> u64 {
+    600
+}
+
+fn default_allow_explicit() -> bool {
+    true
+}
+
+fn default_max_per_user() -> usize {
+    2
+}
+
+fn default_added_template() -> String {
+    String::from("Added {artist} - {title} to the queue")
+}
+
+impl Default for SongRequestConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_duration_secs: default_max_duration_secs(),
+            allow_explicit: default_allow_explicit(),
+            max_per_user: default_max_per_user(),
+            blocked_tracks: Vec::new(),
+            blocked_artists: Vec::new(),
+            added_template: default_added_template(),
+        }
+    }
+}
+
+// a track found for a request, id is what the source queues it by
+#[derive(Debug, Clone)]
+pub struct RequestedTrack {
+    pub track: TrackMetadata,
+    pub id: String,
+    pub explicit: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct SongRequest {
+    pub track: TrackMetadata,
+    pub requester: String,
+}
+
+#[derive(Debug)]
+pub enum Rejection {
+    NoSource,
+    UserLimit(usize),
+    NotFound,
+    TooLong(u64),
+    Explicit,
+    Blocked,
+    Failed(Error),
+}
+
+// sent back to the requester in chat
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::NoSource => write!(f, "Song requests are not available right now."),
+            Rejection::UserLimit(max) => {
+                write!(f, "You already have {} songs waiting in the queue.", max)
+            }
+            Rejection::NotFound => write!(f, "No song found for that."),
+            Rejection::TooLong(secs) => write!(
+                f,
+                "That song is longer than {}.",
+                template::format_ms(secs * 1000)
+            ),
+            Rejection::Explicit => write!(f, "Explicit songs can't be requested."),
+            Rejection::Blocked => write!(f, "That song can't be requested."),
+            Rejection::Failed(e) => write!(f, "Couldn't queue the song. {}", e.message),
+        }
+    }
+}
+
+impl SongRequestConfig {
+    fn check(&self, requested: &RequestedTrack) -> Result<(), Rejection> {
+        let track = &requested.track;
+
+        if self.max_duration_secs > 0
+            && track
+                .duration_ms
+                .is_some_and(|ms| ms > self.max_duration_secs * 1000)
+        {
+            return Err(Rejection::TooLong(self.max_duration_secs));
+        }
+
+        if requested.explicit && !self.allow_explicit {
+            return Err(Rejection::Explicit);
+        }
+
+        let blocked_track = self
+            .blocked_tracks
+            .iter()
+            .any(|b| b.contains(&requested.id));
+        let blocked_artist = track.artists.iter().any(|a| {
+            self.blocked_artists
+                .iter()
+                .any(|b| b.eq_ignore_ascii_case(a))
+        });
+
+        if blocked_track || blocked_artist {
+            return Err(Rejection::Blocked);
+        }
+
+        Ok(())
+    }
+}
+
+// requests of one channel that have been queued but not played yet
+#[derive(Debug, Default)]
+pub struct SongRequests {
+    pending: Mutex<VecDeque<SongRequest>>,
+}
+
+impl SongRequests {
+    pub async fn request(
+        &self,
+        config: &SongRequestConfig,
+        sources: &SourceRegistry,
+        query: &str,
+        requester: &str,
+        role: Role,
+    ) -> Result<SongRequest, Rejection> {
+        let source = sources.request_source().ok_or(Rejection::NoSource)?;
+
+        if role < Role::Moderator {
+            let waiting = self
+                .pending
+                .lock()
+                .await
+                .iter()
+                .filter(|r| r.requester.eq_ignore_ascii_case(requester))
+                .count();
+            if waiting >= config.max_per_user {
+                return Err(Rejection::UserLimit(config.max_per_user));
+            }
+        }
+
+        let requested = source
+            .find_track(query)
+            .await
+            .map_err(Rejection::Failed)?
+            .ok_or(Rejection::NotFound)?;
+
+        config.check(&requested)?;
+
+        source
+            .queue_track(&requested)
+            .await
+            .map_err(Rejection::Failed)?;
+
+        let request = SongRequest {
+            track: requested.track,
+            requester: requester.to_string(),
+        };
+        self.pending.lock().await.push_back(request.clone());
+
+        Ok(request)
+    }
+
+    pub async fn pending(&self) -> Vec<SongRequest> {
+        self.pending.lock().await.iter().cloned().collect()
+    }
+
+    // the queue plays in order, so everything before a request that starts playing was played or skipped
+    pub async fn played(&self, track: &TrackMetadata) {
+        let mut pending = self.pending.lock().await;
+
+        if let Some(i) = pending.iter().position(|r| r.track.same_track(track)) {
+            pending.drain(..=i);
+        }
+    }
+
+    pub async fn run(self: Arc<Self>, tracker: Arc<Tracker>) {
+        let mut changes = tracker.subscribe();
+
+        loop {
+            match changes.recv().await {
+                Ok(track) => self.played(&track).await,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+}
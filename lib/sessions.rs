@@ -0,0 +1,345 @@
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use chrono::{TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+
+use crate::errors::{Error, ErrorKind, NPResult};
+use crate::history::HistoryEntry;
+use crate::track::TrackMetadata;
+use crate::tracker::Tracker;
+use crate::Config;
+
+const SESSIONS_DIR: &str = "sessions";
+
+// the songs played in one channel from the start of the bot until it stops
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Session {
+    pub channel: String,
+    // unix timestamp
+    pub started_at: i64,
+    pub tracks: Vec<HistoryEntry>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    Csv,
+    Json,
+    Text,
+}
+
+impl FromStr for ExportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "json" => Ok(ExportFormat::Json),
+            "text" | "txt" => Ok(ExportFormat::Text),
+            _ => Err(Error::new(
+                format!("Unknown export format \"{}\", use csv, json or text", s),
+                ErrorKind::ParseError,
+            )),
+        }
+    }
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Json => "application/json",
+            ExportFormat::Text => "text/plain; charset=utf-8",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Json => "json",
+            ExportFormat::Text => "txt",
+        }
+    }
+}
+
+impl Session {
+    pub fn new(channel: &str) -> Self {
+        Self {
+            channel: channel.to_string(),
+            started_at: Utc::now().timestamp(),
+            tracks: Vec::new(),
+        }
+    }
+
+    // also the file name, without the extension
+    pub fn id(&self) -> String {
+        format!(
+            "{}-{}",
+            self.channel,
+            format_time(self.started_at, "%Y%m%d-%H%M%S")
+        )
+    }
+
+    pub fn export(&self, format: ExportFormat) -> NPResult<String> {
+        match format {
+            ExportFormat::Json => Ok(serde_json::to_string_pretty(self)?),
+            ExportFormat::Csv => Ok(self.to_csv()),
+            ExportFormat::Text => Ok(self.to_text()),
+        }
+    }
+
+    fn to_csv(&self) -> String {
+        let mut csv = String::from("offset,played_at,artist,title,album,url,source\n");
+
+        for entry in &self.tracks {
+            let track = &entry.track;
+            let fields = [
+                self.offset(entry),
+                format_time(entry.played_at, "%Y-%m-%dT%H:%M:%SZ"),
+                track.artist(),
+                track.title.clone(),
+                track.album.clone().unwrap_or_default(),
+                track.url.clone(),
+                track.source.clone(),
+            ];
+
+            let row: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+            csv.push_str(&row.join(","));
+            csv.push('\n');
+        }
+
+        csv
+    }
+
+    // one line per song, timestamped from the start of the session like VOD chapters
+    fn to_text(&self) -> String {
+        let mut text = String::new();
+
+        for entry in &self.tracks {
+            let _ = writeln!(
+                text,
+                "{} {} - {}",
+                self.offset(entry),
+                entry.track.artist(),
+                entry.track.title
+            );
+        }
+
+        text
+    }
+
+    fn offset(&self, entry: &HistoryEntry) -> String {
+        let secs = (entry.played_at - self.started_at).max(0);
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    }
+}
+
+// quoted only when needed, as spreadsheets expect
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn format_time(timestamp: i64, format: &str) -> String {
+    Utc.timestamp_opt(timestamp, 0)
+        .single()
+        .map(|t| t.format(format).to_string())
+        .unwrap_or_default()
+}
+
+// records the session of one channel, the file is written once the first song plays
+#[derive(Debug)]
+pub struct SessionRecorder {
+    session: Mutex<Session>,
+    path: Option<PathBuf>,
+}
+
+impl SessionRecorder {
+    pub fn new(channel: &str) -> Self {
+        let session = Session::new(channel);
+
+        let path = match sessions_dir() {
+            Ok(dir) => Some(dir.join(format!("{}.json", session.id()))),
+            Err(e) => {
+                tracing::warn!("The session of {} will not be saved: {}", channel, e);
+                None
+            }
+        };
+
+        Self {
+            session: Mutex::new(session),
+            path,
+        }
+    }
+
+    pub async fn record(&self, track: &TrackMetadata) {
+        let mut session = self.session.lock().await;
+
+        if session
+            .tracks
+            .last()
+            .is_some_and(|e| e.track.same_track(track))
+        {
+            return;
+        }
+
+        let mut track = track.clone();
+        track.progress_ms = None;
+        session.tracks.push(HistoryEntry {
+            track,
+            played_at: Utc::now().timestamp(),
+        });
+
+        if let Some(path) = &self.path {
+            if let Err(e) = write(path, &session) {
+                tracing::error!("Failed to save the session: {}", e);
+            }
+        }
+    }
+
+    pub async fn run(self: Arc<Self>, tracker: Arc<Tracker>) {
+        let mut changes = tracker.subscribe();
+
+        // the song playing when the channel was joined starts the session
+        if let Some(track) = tracker.cached().await {
+            self.record(&track).await;
+        }
+
+        loop {
+            match changes.recv().await {
+                Ok(track) => self.record(&track).await,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+fn write(path: &Path, session: &Session) -> NPResult<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, serde_json::to_string(session)?)?;
+    Ok(())
+}
+
+// next to the config file
+pub fn sessions_dir() -> NPResult<PathBuf> {
+    Ok(Config::dir()?.join(SESSIONS_DIR))
+}
+
+// newest first, files that can not be read are skipped
+pub fn list() -> NPResult<Vec<Session>> {
+    let dir = sessions_dir()?;
+
+    let files = match std::fs::read_dir(&dir) {
+        Ok(files) => files,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut sessions: Vec<Session> = files
+        .filter_map(|f| f.ok())
+        .map(|f| f.path())
+        .filter(|p| p.extension().is_some_and(|e| e == "json"))
+        .filter_map(|p| {
+            let data = std::fs::read(&p).ok()?;
+            match serde_json::from_slice(&data) {
+                Ok(session) => Some(session),
+                Err(e) => {
+                    tracing::warn!("Ignoring the session in {}: {}", p.display(), e);
+                    None
+                }
+            }
+        })
+        .collect();
+
+    sessions.sort_by_key(|s| std::cmp::Reverse(s.started_at));
+
+    Ok(sessions)
+}
+
+// None picks the newest session, of the channel when one is given
+pub fn find(id: Option<&str>, channel: Option<&str>) -> NPResult<Session> {
+    let found = list()?.into_iter().find(|s| match id {
+        Some(id) => s.id() == id,
+        None => match channel {
+            Some(channel) => s.channel.eq_ignore_ascii_case(channel),
+            None => true,
+        },
+    });
+
+    found.ok_or_else(|| {
+        let what = match (id, channel) {
+            (Some(id), _) => format!("No session {} found", id),
+            (None, Some(channel)) => format!("No session found for {}", channel),
+            (None, None) => String::from("No session found"),
+        };
+        Error::new(what, ErrorKind::FileError)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(track: TrackMetadata) -> Session {
+        Session {
+            channel: String::from("streamer"),
+            started_at: 1_700_000_000,
+            tracks: vec![HistoryEntry {
+                track,
+                played_at: 1_700_003_725,
+            }],
+        }
+    }
+
+    #[test]
+    fn writes_plain_fields_as_they_are() {
+        let csv = session(TrackMetadata {
+            title: String::from("Windowlicker"),
+            artists: vec![String::from("Aphex Twin")],
+            url: String::from("https://example.com/track"),
+            source: String::from("spotify"),
+            ..Default::default()
+        })
+        .to_csv();
+
+        assert_eq!(
+            csv,
+            "offset,played_at,artist,title,album,url,source\n\
+             1:02:05,2023-11-14T23:15:25Z,Aphex Twin,Windowlicker,,https://example.com/track,spotify\n"
+        );
+    }
+
+    #[test]
+    fn quotes_fields_that_need_it() {
+        let csv = session(TrackMetadata {
+            title: String::from("Say \"Hi\""),
+            artists: vec![String::from("One"), String::from("Two")],
+            album: Some(String::from("Line\nbreak")),
+            ..Default::default()
+        })
+        .to_csv();
+
+        let row = csv.split_once('\n').unwrap().1;
+        assert_eq!(
+            row,
+            "1:02:05,2023-11-14T23:15:25Z,\"One, Two\",\"Say \"\"Hi\"\"\",\"Line\nbreak\",,\n"
+        );
+    }
+
+    #[test]
+    fn quotes_carriage_returns() {
+        assert_eq!(csv_field("a\rb"), "\"a\rb\"");
+        assert_eq!(csv_field(""), "");
+    }
+}
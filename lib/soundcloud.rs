@@ -1,29 +1,78 @@
+use async_trait::async_trait;
 use serde::Deserialize;
 
-use crate::twitch::Song;
+use crate::{
+    errors::{Error, ErrorKind, NPResult},
+    history::HistoryEntry,
+    sources::MusicSource,
+    track::TrackMetadata,
+    Config,
+};
 
-const PLAY_HISTORY_URL: &str = "https://api-v2.soundcloud.com/me/play-history/tracks?limit=1";
+pub const SOURCE_NAME: &str = "soundcloud";
+
+const PLAY_HISTORY_URL: &str = "https://api-v2.soundcloud.com/me/play-history/tracks";
+
+// the most the play history endpoint returns at once
+const MAX_HISTORY_LIMIT: usize = 200;
 
 #[derive(Debug, Clone)]
 pub struct SoundcloudClient {
     http_client: reqwest::Client,
     oauth: String,
+    enabled: bool,
+    priority: usize,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct TrackInfo {
     pub permalink_url: String,
+    pub title: String,
+    pub duration: Option<u64>,
+    pub artwork_url: Option<String>,
+    pub user: Option<UserInfo>,
+    pub publisher_metadata: Option<PublisherMetadata>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserInfo {
+    pub username: String,
 }
 
-impl Song for TrackInfo {
-    fn url(&self) -> String {
-        self.permalink_url.clone()
+#[derive(Debug, Clone, Deserialize)]
+pub struct PublisherMetadata {
+    pub artist: Option<String>,
+    pub album_title: Option<String>,
+}
+
+impl TrackInfo {
+    pub fn to_metadata(&self) -> TrackMetadata {
+        let publisher = self.publisher_metadata.as_ref();
+
+        // the uploader is the best guess we have when no publisher info is attached
+        let artist = publisher
+            .and_then(|p| p.artist.clone())
+            .or_else(|| self.user.as_ref().map(|u| u.username.clone()));
+
+        TrackMetadata {
+            title: self.title.clone(),
+            artists: artist.into_iter().collect(),
+            album: publisher.and_then(|p| p.album_title.clone()),
+            duration_ms: self.duration,
+            progress_ms: None,
+            artwork_url: self.artwork_url.clone(),
+            url: self.permalink_url.clone(),
+            source: SOURCE_NAME.to_string(),
+        }
     }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 struct CollectionItem {
     track: TrackInfo,
+    // unix timestamp in milliseconds
+    #[serde(default)]
+    played_at: Option<i64>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -32,32 +81,82 @@ struct SoundCloudData {
 }
 
 impl SoundcloudClient {
-    pub fn new(oauth: Option<String>) -> Option<Self> {
-        match oauth {
-            Some(oauth) => Some(Self {
-                http_client: reqwest::Client::new(),
-                oauth,
-            }),
-            None => None,
-        }
+    pub fn new(oauth: Option<String>, enabled: bool, priority: usize) -> Option<Self> {
+        oauth.map(|oauth| Self {
+            http_client: reqwest::Client::new(),
+            oauth,
+            enabled,
+            priority,
+        })
     }
 
-    pub async fn fetch_current_song(&self) -> Result<TrackInfo, Box<dyn std::error::Error>> {
-        let resp = self
-            .http_client
-            .get(PLAY_HISTORY_URL)
-            .header("Authorization", format!("{}", self.oauth))
-            .send()
-            .await?
-            .json::<SoundCloudData>()
-            .await?;
+    pub fn from_config(conf: &Config) -> Option<Self> {
+        Self::new(
+            conf.soundcloud_oauth.clone(),
+            conf.soundcloud_enabled,
+            conf.source_priority(SOURCE_NAME),
+        )
+    }
+
+    pub async fn fetch_current_song(&self) -> NPResult<TrackInfo> {
+        let resp = self.play_history(1).await?;
 
         let track_info = &resp
             .collection
             .first()
-            .ok_or("No tracks found in play history")?
+            .ok_or(Error::new(
+                String::from("No tracks found in play history"),
+                ErrorKind::SoundcloudError,
+            ))?
             .track;
 
         Ok(track_info.clone())
     }
+
+    async fn play_history(&self, limit: usize) -> NPResult<SoundCloudData> {
+        Ok(self
+            .http_client
+            .get(PLAY_HISTORY_URL)
+            .query(&[("limit", limit.clamp(1, MAX_HISTORY_LIMIT))])
+            .header("Authorization", &self.oauth)
+            .send()
+            .await?
+            .json::<SoundCloudData>()
+            .await?)
+    }
+}
+
+#[async_trait]
+impl MusicSource for SoundcloudClient {
+    fn name(&self) -> &str {
+        SOURCE_NAME
+    }
+
+    fn priority(&self) -> usize {
+        self.priority
+    }
+
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    async fn fetch_current_song(&self) -> NPResult<Option<TrackMetadata>> {
+        let track = SoundcloudClient::fetch_current_song(self).await?;
+        Ok(Some(track.to_metadata()))
+    }
+
+    async fn recent_tracks(&self, limit: usize) -> NPResult<Vec<HistoryEntry>> {
+        let resp = self.play_history(limit).await?;
+
+        Ok(resp
+            .collection
+            .iter()
+            .filter_map(|item| {
+                Some(HistoryEntry {
+                    track: item.track.to_metadata(),
+                    played_at: item.played_at? / 1000,
+                })
+            })
+            .collect())
+    }
 }
@@ -0,0 +1,176 @@
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+
+use crate::errors::{Error, ErrorKind, NPResult};
+use crate::history::HistoryEntry;
+use crate::playback::Playback;
+use crate::requests::RequestedTrack;
+use crate::track::TrackMetadata;
+
+#[async_trait]
+pub trait MusicSource: Send + Sync + std::fmt::Debug {
+    fn name(&self) -> &str;
+
+    // lower values are asked first
+    fn priority(&self) -> usize;
+
+    fn enabled(&self) -> bool;
+
+    async fn fetch_current_song(&self) -> NPResult<Option<TrackMetadata>>;
+
+    // sources that can play song requests override these three
+    fn takes_requests(&self) -> bool {
+        false
+    }
+
+    // query is a link to a track or words to search for
+    async fn find_track(&self, _query: &str) -> NPResult<Option<RequestedTrack>> {
+        Err(unsupported(self.name(), "take song requests"))
+    }
+
+    async fn queue_track(&self, _track: &RequestedTrack) -> NPResult<()> {
+        Err(unsupported(self.name(), "take song requests"))
+    }
+
+    // sources that can skip, pause, resume and change the volume override these two
+    fn controls_playback(&self) -> bool {
+        false
+    }
+
+    async fn control(&self, _action: Playback) -> NPResult<()> {
+        Err(unsupported(self.name(), "control playback"))
+    }
+
+    // the songs the source played before the bot started, newest first
+    async fn recent_tracks(&self, _limit: usize) -> NPResult<Vec<HistoryEntry>> {
+        Ok(Vec::new())
+    }
+}
+
+fn unsupported(name: &str, what: &str) -> Error {
+    Error::new(
+        format!("{} can not {}", name, what),
+        ErrorKind::UnknownError,
+    )
+}
+
+#[derive(Debug, Default)]
+struct Sources {
+    sources: Vec<Arc<dyn MusicSource>>,
+    fallback: bool,
+}
+
+// clones share the same sources, so a config reload can swap them while the tracker runs
+#[derive(Debug, Clone, Default)]
+pub struct SourceRegistry {
+    inner: Arc<RwLock<Sources>>,
+}
+
+impl SourceRegistry {
+    pub fn new(fallback: bool) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(Sources {
+                sources: Vec::new(),
+                fallback,
+            })),
+        }
+    }
+
+    pub fn register(&self, source: Arc<dyn MusicSource>) {
+        tracing::info!(
+            "Registered music source {} (priority {})",
+            source.name(),
+            source.priority()
+        );
+
+        let mut inner = self.inner.write().unwrap();
+        inner.sources.push(source);
+        inner.sources.sort_by_key(|s| s.priority());
+    }
+
+    // swaps the source with this name for a new one, or removes it when there is none
+    pub fn replace(&self, name: &str, source: Option<Arc<dyn MusicSource>>) {
+        self.inner
+            .write()
+            .unwrap()
+            .sources
+            .retain(|s| s.name() != name);
+
+        match source {
+            Some(source) => self.register(source),
+            None => tracing::info!("Removed music source {}", name),
+        }
+    }
+
+    pub fn set_fallback(&self, fallback: bool) {
+        self.inner.write().unwrap().fallback = fallback;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.enabled().is_empty()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.enabled()
+            .iter()
+            .map(|s| s.name().to_string())
+            .collect()
+    }
+
+    // the first enabled source that takes song requests
+    pub fn request_source(&self) -> Option<Arc<dyn MusicSource>> {
+        self.enabled().into_iter().find(|s| s.takes_requests())
+    }
+
+    // the first enabled source that controls playback
+    pub fn playback_source(&self) -> Option<Arc<dyn MusicSource>> {
+        self.enabled().into_iter().find(|s| s.controls_playback())
+    }
+
+    fn enabled(&self) -> Vec<Arc<dyn MusicSource>> {
+        self.inner
+            .read()
+            .unwrap()
+            .sources
+            .iter()
+            .filter(|s| s.enabled())
+            .cloned()
+            .collect()
+    }
+
+    pub async fn now_playing(&self) -> Option<TrackMetadata> {
+        let fallback = self.inner.read().unwrap().fallback;
+
+        for source in self.enabled() {
+            match source.fetch_current_song().await {
+                Ok(Some(song)) => return Some(song),
+                Ok(None) => tracing::debug!("Nothing playing on {}", source.name()),
+                Err(e) => tracing::error!("{}", e),
+            }
+
+            if !fallback {
+                break;
+            }
+        }
+
+        None
+    }
+
+    // from the first source that remembers anything
+    pub async fn recent_tracks(&self, limit: usize) -> Option<Vec<HistoryEntry>> {
+        for source in self.enabled() {
+            match source.recent_tracks(limit).await {
+                Ok(tracks) if !tracks.is_empty() => return Some(tracks),
+                Ok(_) => {}
+                Err(e) => tracing::warn!(
+                    "Could not read the play history of {}: {}",
+                    source.name(),
+                    e
+                ),
+            }
+        }
+
+        None
+    }
+}
@@ -1,28 +1,76 @@
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use async_trait::async_trait;
 use base64::{engine::general_purpose, Engine as _};
 use hyper::StatusCode;
 use serde::Deserialize;
+use tokio::sync::Mutex;
 
 use crate::{
     errors::{Error, ErrorKind, NPResult},
-    twitch::Song,
+    layers::ConfigSource,
+    playback::Playback,
+    requests::RequestedTrack,
+    sources::MusicSource,
+    track::TrackMetadata,
+    Config,
 };
 
+pub const SOURCE_NAME: &str = "spotify";
+
 pub const NOW_PLAYING_URL: &str = "https://api.spotify.com/v1/me/player/currently-playing";
 
+pub const QUEUE_URL: &str = "https://api.spotify.com/v1/me/player/queue";
+
+pub const PLAYER_URL: &str = "https://api.spotify.com/v1/me/player";
+
+pub const TRACKS_URL: &str = "https://api.spotify.com/v1/tracks";
+
+pub const SEARCH_URL: &str = "https://api.spotify.com/v1/search";
+
 pub const CALLBACK_URI: &str = "http://localhost:3000/spotifycallback";
 
 pub const TOKEN_URL: &str = "https://accounts.spotify.com/api/token";
 
+// access tokens are refreshed this long before they expire
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
 #[derive(Debug, Clone)]
 pub struct SpotifyClient {
     http_client: reqwest::Client,
+    config: Arc<Mutex<Config>>,
     client_id: String,
     client_secret: String,
-    access_token: String,
-    refresh_token: String,
-    //expires_in: Duration,
+    tokens: Arc<Mutex<SpotifyTokens>>,
+    enabled: bool,
+    priority: usize,
+    // refreshed tokens are saved to this channel when it has its own
+    channel: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SpotifyTokens {
+    pub access_token: String,
+    pub refresh_token: String,
+    // None when the tokens were loaded from the config and their age is unknown
+    pub expires_at: Option<Instant>,
+}
+
+impl SpotifyTokens {
+    pub fn new(access_token: String, refresh_token: String, expires_in: Option<u64>) -> Self {
+        Self {
+            access_token,
+            refresh_token,
+            expires_at: expires_in.map(|secs| Instant::now() + Duration::from_secs(secs)),
+        }
+    }
+
+    fn expires_soon(&self) -> bool {
+        self.expires_at
+            .is_some_and(|at| at.saturating_duration_since(Instant::now()) < REFRESH_MARGIN)
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -37,41 +85,114 @@ pub struct AuthResponse {
 #[derive(Debug, Deserialize)]
 struct RefreshTokenResponse {
     access_token: String,
-    refresh_token: String,
+    expires_in: u64,
+    // spotify only sends a new refresh token sometimes, otherwise the old one stays valid
+    refresh_token: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct SpotifyTrack {
-    id: String,
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub artists: Vec<SpotifyArtist>,
+    pub album: Option<SpotifyAlbum>,
+    pub duration_ms: Option<u64>,
+    #[serde(default)]
+    pub explicit: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpotifyArtist {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpotifyAlbum {
+    pub name: String,
+    #[serde(default)]
+    pub images: Vec<SpotifyImage>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpotifyImage {
+    pub url: String,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct CurrentlyPlayingResponse {
     item: Option<SpotifyTrack>,
+    progress_ms: Option<u64>,
     is_playing: bool,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+struct SearchResponse {
+    tracks: SearchTracks,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SearchTracks {
+    items: Vec<SpotifyTrack>,
+}
+
 impl SpotifyTrack {
     fn to_spotify_track_link(&self) -> String {
         format!("https://open.spotify.com/track/{}", &self.id)
     }
-}
 
-impl Song for SpotifyTrack {
-    fn url(&self) -> String {
-        self.to_spotify_track_link()
+    pub fn to_metadata(&self, progress_ms: Option<u64>) -> TrackMetadata {
+        TrackMetadata {
+            title: self.name.clone(),
+            artists: self.artists.iter().map(|a| a.name.clone()).collect(),
+            album: self.album.as_ref().map(|a| a.name.clone()),
+            duration_ms: self.duration_ms,
+            progress_ms,
+            // spotify lists the largest image first
+            artwork_url: self
+                .album
+                .as_ref()
+                .and_then(|a| a.images.first())
+                .map(|i| i.url.clone()),
+            url: self.to_spotify_track_link(),
+            source: SOURCE_NAME.to_string(),
+        }
     }
 }
 
+// modifying playback is needed to queue song requests
 pub fn make_oauth_url(client_id: &str, callback_uri: &str) -> String {
     format!(
-        "https://accounts.spotify.com/authorize?client_id={}&redirect_uri={}&scope=user-read-currently-playing&response_type=code",
+        "https://accounts.spotify.com/authorize?client_id={}&redirect_uri={}&scope=user-read-currently-playing%20user-modify-playback-state&response_type=code",
         client_id, callback_uri
     )
 }
 
+// accepts open.spotify.com links (with or without a locale or query) and spotify:track: uris
+pub fn parse_track_id(input: &str) -> Option<String> {
+    let input = input.trim();
+
+    let id = if let Some(id) = input.strip_prefix("spotify:track:") {
+        id
+    } else {
+        let path = input
+            .strip_prefix("https://")
+            .or_else(|| input.strip_prefix("http://"))
+            .unwrap_or(input)
+            .strip_prefix("open.spotify.com/")?;
+        let path = path.split(['?', '#']).next()?;
+
+        let mut segments = path.split('/');
+        segments.find(|s| *s == "track")?;
+        segments.next()?
+    };
+
+    let valid = !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric());
+    valid.then(|| id.to_string())
+}
+
 fn make_auth_token(client_id: &str, client_secret: &str) -> String {
-    general_purpose::URL_SAFE_NO_PAD.encode(format!("{}:{}", client_id, client_secret))
+    general_purpose::STANDARD.encode(format!("{}:{}", client_id, client_secret))
 }
 
 pub async fn exchange_code(
@@ -117,35 +238,100 @@ pub async fn exchange_code(
 
 impl SpotifyClient {
     pub fn new(
+        config: Arc<Mutex<Config>>,
         client_id: String,
         client_secret: String,
-        access_token: String,
-        refresh_token: String,
-        //expires_in: Duration,
+        tokens: SpotifyTokens,
+        enabled: bool,
+        priority: usize,
     ) -> Self {
         Self {
             http_client: reqwest::Client::new(),
+            config,
             client_id,
             client_secret,
-            access_token,
-            refresh_token,
-            //expires_in,
+            tokens: Arc::new(Mutex::new(tokens)),
+            enabled,
+            priority,
+            channel: None,
+        }
+    }
+
+    // tokens from a fresh authorization win over the ones saved in the config, conf is the
+    // effective config of channel when there is one, see Config::for_channel
+    pub fn from_config(
+        config: Arc<Mutex<Config>>,
+        conf: &Config,
+        tokens: Option<SpotifyTokens>,
+        channel: Option<&str>,
+    ) -> Option<Self> {
+        if !conf.spotify_enabled {
+            return None;
+        }
+
+        let tokens = tokens.or_else(|| {
+            Some(SpotifyTokens::new(
+                conf.spotify_oauth.clone()?,
+                conf.spotify_oauth_refresh.clone()?,
+                None,
+            ))
+        });
+
+        let tokens = match tokens {
+            Some(tokens) => tokens,
+            None => {
+                tracing::warn!("Spotify is enabled but has not been authorized.");
+                return None;
+            }
+        };
+
+        let mut client = Self::new(
+            config,
+            conf.spotify_client_id.clone()?,
+            conf.spotify_client_secret.clone()?,
+            tokens,
+            true,
+            conf.source_priority(SOURCE_NAME),
+        );
+        client.channel = channel.map(String::from);
+
+        Some(client)
+    }
+
+    // sends the request with the current access token, refreshing and retrying once if spotify rejects it
+    async fn send<F>(&self, request: F) -> NPResult<reqwest::Response>
+    where
+        F: Fn(&str) -> reqwest::RequestBuilder,
+    {
+        if self.tokens.lock().await.expires_soon() {
+            tracing::debug!("Spotify access token is about to expire, refreshing");
+            self.do_refresh_token().await?;
         }
+
+        let access_token = self.tokens.lock().await.access_token.clone();
+        let resp = request(&access_token).send().await?;
+
+        if resp.status() != StatusCode::UNAUTHORIZED {
+            return Ok(resp);
+        }
+
+        tracing::info!("Spotify rejected the access token, refreshing");
+        self.do_refresh_token().await?;
+
+        let access_token = self.tokens.lock().await.access_token.clone();
+        Ok(request(&access_token).send().await?)
     }
 
-    pub async fn fetch_current_song(
-        &mut self,
-    ) -> Result<Option<SpotifyTrack>, Box<dyn std::error::Error>> {
+    pub async fn fetch_current_song(&self) -> NPResult<Option<TrackMetadata>> {
         let resp = self
-            .http_client
-            .get(NOW_PLAYING_URL)
-            .header("Authorization", format!("Bearer {}", self.access_token))
-            .send()
+            .send(|token| self.http_client.get(NOW_PLAYING_URL).bearer_auth(token))
             .await?;
 
         if resp.status() == StatusCode::UNAUTHORIZED {
-            self.do_refresh_token().await?;
-            return Err("token refresh needed, done".into()); // TODO: make this auto run again
+            return Err(Error::new(
+                String::from("Spotify rejected the refreshed access token"),
+                ErrorKind::SpotifyError,
+            ));
         }
 
         if resp.status() != StatusCode::OK {
@@ -158,17 +344,123 @@ impl SpotifyClient {
             return Ok(None);
         }
 
-        Ok(Some(track.item.clone().ok_or("No track playing")?))
+        let item = track.item.as_ref().ok_or(Error::new(
+            String::from("No track playing"),
+            ErrorKind::SpotifyError,
+        ))?;
+
+        Ok(Some(item.to_metadata(track.progress_ms)))
     }
 
-    pub async fn do_refresh_token(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+    pub async fn get_track(&self, id: &str) -> NPResult<Option<SpotifyTrack>> {
+        let url = format!("{}/{}", TRACKS_URL, id);
+        let resp = self
+            .send(|token| self.http_client.get(&url).bearer_auth(token))
+            .await?;
+
+        match resp.status() {
+            StatusCode::OK => Ok(Some(resp.json().await?)),
+            StatusCode::NOT_FOUND | StatusCode::BAD_REQUEST => Ok(None),
+            status => Err(Error::new(
+                format!("Failed to look up the track, got status {}", status),
+                ErrorKind::SpotifyError,
+            )),
+        }
+    }
+
+    pub async fn search_track(&self, query: &str) -> NPResult<Option<SpotifyTrack>> {
+        let resp = self
+            .send(|token| {
+                self.http_client
+                    .get(SEARCH_URL)
+                    .query(&[("q", query), ("type", "track"), ("limit", "1")])
+                    .bearer_auth(token)
+            })
+            .await?;
+
+        if resp.status() != StatusCode::OK {
+            return Err(Error::new(
+                format!(
+                    "Failed to search for the track, got status {}",
+                    resp.status()
+                ),
+                ErrorKind::SpotifyError,
+            ));
+        }
+
+        let results = resp.json::<SearchResponse>().await?;
+        Ok(results.tracks.items.into_iter().next())
+    }
+
+    pub async fn add_to_queue(&self, id: &str) -> NPResult<()> {
+        let uri = format!("spotify:track:{}", id);
+        let resp = self
+            .send(|token| {
+                self.http_client
+                    .post(QUEUE_URL)
+                    .query(&[("uri", uri.as_str())])
+                    .header("Content-Length", "0")
+                    .bearer_auth(token)
+            })
+            .await?;
+
+        player_result(resp.status())
+    }
+
+    pub async fn control_playback(&self, action: Playback) -> NPResult<()> {
+        let resp = match action {
+            Playback::Skip => {
+                self.send(|token| {
+                    self.http_client
+                        .post(format!("{}/next", PLAYER_URL))
+                        .header("Content-Length", "0")
+                        .bearer_auth(token)
+                })
+                .await?
+            }
+            Playback::Pause => {
+                self.send(|token| {
+                    self.http_client
+                        .put(format!("{}/pause", PLAYER_URL))
+                        .header("Content-Length", "0")
+                        .bearer_auth(token)
+                })
+                .await?
+            }
+            Playback::Resume => {
+                self.send(|token| {
+                    self.http_client
+                        .put(format!("{}/play", PLAYER_URL))
+                        .header("Content-Length", "0")
+                        .bearer_auth(token)
+                })
+                .await?
+            }
+            Playback::Volume(percent) => {
+                self.send(|token| {
+                    self.http_client
+                        .put(format!("{}/volume", PLAYER_URL))
+                        .query(&[("volume_percent", percent)])
+                        .header("Content-Length", "0")
+                        .bearer_auth(token)
+                })
+                .await?
+            }
+        };
+
+        player_result(resp.status())
+    }
+
+    pub async fn do_refresh_token(&self) -> NPResult<()> {
+        let mut tokens = self.tokens.lock().await;
+
         let mut form_data = HashMap::new();
         form_data.insert("grant_type", "refresh_token");
-        form_data.insert("refresh_token", &self.refresh_token);
+        form_data.insert("refresh_token", &tokens.refresh_token);
 
-        let response: RefreshTokenResponse = self
+        let response = self
             .http_client
-            .post("https://id.twitch.tv/oauth2/token")
+            .post(TOKEN_URL)
             .header("Content-Type", "application/x-www-form-urlencoded")
             .header(
                 "Authorization",
@@ -179,13 +471,190 @@ impl SpotifyClient {
             )
             .form(&form_data)
             .send()
-            .await?
-            .json()
             .await?;
 
-        self.access_token = response.access_token;
-        self.refresh_token = response.refresh_token;
+        if response.status() != StatusCode::OK {
+            return Err(Error::new(
+                format!(
+                    "Failed to refresh Spotify token, expected status 200, got {}",
+                    response.status()
+                ),
+                ErrorKind::SpotifyError,
+            ));
+        }
+
+        let response: RefreshTokenResponse = response.json().await?;
+
+        *tokens = SpotifyTokens::new(
+            response.access_token,
+            response
+                .refresh_token
+                .unwrap_or_else(|| tokens.refresh_token.clone()),
+            Some(response.expires_in),
+        );
+
+        tracing::info!("Refreshed Spotify OAuth");
+
+        let snapshot = {
+            let mut conf = self.config.lock().await;
+            conf.layer(ConfigSource::Runtime, |c| {
+                // channels without their own tokens share the top level ones
+                let own = self
+                    .channel
+                    .as_ref()
+                    .and_then(|name| c.channels.get_mut(name))
+                    .filter(|channel| channel.spotify_oauth_refresh.is_some());
+
+                let (oauth, refresh) = match own {
+                    Some(channel) => (
+                        &mut channel.spotify_oauth,
+                        &mut channel.spotify_oauth_refresh,
+                    ),
+                    None => (&mut c.spotify_oauth, &mut c.spotify_oauth_refresh),
+                };
+                *oauth = Some(tokens.access_token.clone());
+                *refresh = Some(tokens.refresh_token.clone());
+            });
+            conf.clone()
+        };
+        drop(tokens);
+
+        if let Err(e) = snapshot.save().await {
+            tracing::error!("Failed to save refreshed Spotify token. {}", e);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MusicSource for SpotifyClient {
+    fn name(&self) -> &str {
+        SOURCE_NAME
+    }
+
+    fn priority(&self) -> usize {
+        self.priority
+    }
+
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    async fn fetch_current_song(&self) -> NPResult<Option<TrackMetadata>> {
+        SpotifyClient::fetch_current_song(self).await
+    }
+
+    fn takes_requests(&self) -> bool {
+        true
+    }
+
+    async fn find_track(&self, query: &str) -> NPResult<Option<RequestedTrack>> {
+        let track = match parse_track_id(query) {
+            Some(id) => self.get_track(&id).await?,
+            None => self.search_track(query).await?,
+        };
+
+        Ok(track.map(|t| RequestedTrack {
+            track: t.to_metadata(None),
+            id: t.id.clone(),
+            explicit: t.explicit,
+        }))
+    }
+
+    async fn queue_track(&self, track: &RequestedTrack) -> NPResult<()> {
+        self.add_to_queue(&track.id).await
+    }
+
+    fn controls_playback(&self) -> bool {
+        true
+    }
+
+    async fn control(&self, action: Playback) -> NPResult<()> {
+        self.control_playback(action).await
+    }
+}
+
+// the answer of the player endpoints that queue songs or control playback
+fn player_result(status: StatusCode) -> NPResult<()> {
+    match status {
+        StatusCode::OK | StatusCode::NO_CONTENT | StatusCode::ACCEPTED => Ok(()),
+        StatusCode::NOT_FOUND => Err(Error::new(
+            String::from("Spotify is not playing on any device."),
+            ErrorKind::SpotifyError,
+        )),
+        // older tokens lack the scope, the player endpoints also need Premium
+        StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED => Err(Error::new(
+            String::from(
+                "Spotify refused it, authorize Spotify again and check the account has Premium.",
+            ),
+            ErrorKind::SpotifyError,
+        )),
+        status => Err(Error::new(
+            format!("Spotify answered with status {}.", status),
+            ErrorKind::SpotifyError,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "4uLU6hMCjMI75M1A2tKUQC";
+
+    #[test]
+    fn parses_track_links() {
+        for input in [
+            format!("https://open.spotify.com/track/{}", ID),
+            format!("http://open.spotify.com/track/{}", ID),
+            format!("open.spotify.com/track/{}", ID),
+            format!("  https://open.spotify.com/track/{}  ", ID),
+        ] {
+            assert_eq!(parse_track_id(&input).as_deref(), Some(ID), "{}", input);
+        }
+    }
+
+    #[test]
+    fn parses_locale_paths() {
+        let input = format!("https://open.spotify.com/intl-de/track/{}", ID);
 
-        Ok(true)
+        assert_eq!(parse_track_id(&input).as_deref(), Some(ID));
+    }
+
+    #[test]
+    fn ignores_query_strings_and_fragments() {
+        for input in [
+            format!("https://open.spotify.com/track/{}?si=abc123&context=x", ID),
+            format!("https://open.spotify.com/track/{}#start", ID),
+        ] {
+            assert_eq!(parse_track_id(&input).as_deref(), Some(ID), "{}", input);
+        }
+    }
+
+    #[test]
+    fn parses_uris() {
+        let input = format!("spotify:track:{}", ID);
+
+        assert_eq!(parse_track_id(&input).as_deref(), Some(ID));
+    }
+
+    #[test]
+    fn rejects_other_input() {
+        for input in [
+            String::from("never gonna give you up"),
+            String::from("https://open.spotify.com/track/"),
+            format!("https://open.spotify.com/album/{}", ID),
+            format!(
+                "https://open.spotify.com/album/{}?highlight=spotify:track:{}",
+                ID, ID
+            ),
+            format!("https://example.com/track/{}", ID),
+            format!("spotify:album:{}", ID),
+            String::from("spotify:track:not-an-id"),
+            String::new(),
+        ] {
+            assert_eq!(parse_track_id(&input), None, "{}", input);
+        }
     }
 }
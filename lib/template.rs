@@ -0,0 +1,185 @@
+use crate::errors::{Error, ErrorKind, NPResult};
+use crate::track::TrackMetadata;
+
+pub const PLACEHOLDERS: &[&str] = &[
+    "title",
+    "artist",
+    "album",
+    "url",
+    "progress",
+    "duration",
+    "source",
+    "requester",
+];
+
+enum Segment<'a> {
+    Text(&'a str),
+    Placeholder(&'a str),
+}
+
+fn parse(template: &str) -> NPResult<Vec<Segment<'_>>> {
+    let mut segments = Vec::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            segments.push(Segment::Text(&rest[..start]));
+        }
+
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => {
+                return Err(Error::new(
+                    format!("Unclosed '{{' in template \"{}\"", template),
+                    ErrorKind::ConfigError,
+                ))
+            }
+        };
+
+        let name = &rest[start + 1..end];
+        if !PLACEHOLDERS.contains(&name) {
+            return Err(Error::new(
+                format!(
+                    "Unknown placeholder {{{}}} in template \"{}\". Available placeholders: {}",
+                    name,
+                    template,
+                    PLACEHOLDERS
+                        .iter()
+                        .map(|p| format!("{{{}}}", p))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+                ErrorKind::ConfigError,
+            ));
+        }
+
+        segments.push(Segment::Placeholder(name));
+        rest = &rest[end + 1..];
+    }
+
+    if !rest.is_empty() {
+        segments.push(Segment::Text(rest));
+    }
+
+    Ok(segments)
+}
+
+pub fn validate(template: &str) -> NPResult<()> {
+    parse(template).map(|_| ())
+}
+
+// placeholders without a value (e.g. {album} for a single or nothing playing) render empty
+pub fn render(template: &str, track: Option<&TrackMetadata>, requester: &str) -> NPResult<String> {
+    let mut out = String::with_capacity(template.len());
+
+    for segment in parse(template)? {
+        match segment {
+            Segment::Text(text) => out.push_str(text),
+            Segment::Placeholder(name) => {
+                let value = match (name, track) {
+                    ("requester", _) => requester.to_string(),
+                    (_, None) => String::new(),
+                    ("title", Some(t)) => t.title.clone(),
+                    ("artist", Some(t)) => t.artist(),
+                    ("album", Some(t)) => t.album.clone().unwrap_or_default(),
+                    ("url", Some(t)) => t.url.clone(),
+                    ("progress", Some(t)) => t.progress_ms.map(format_ms).unwrap_or_default(),
+                    ("duration", Some(t)) => t.duration_ms.map(format_ms).unwrap_or_default(),
+                    ("source", Some(t)) => t.source.clone(),
+                    _ => String::new(),
+                };
+                out.push_str(&value);
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+pub fn format_ms(ms: u64) -> String {
+    let secs = ms / 1000;
+    let (hours, minutes, seconds) = (secs / 3600, (secs / 60) % 60, secs % 60);
+
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track() -> TrackMetadata {
+        TrackMetadata {
+            title: String::from("Windowlicker"),
+            artists: vec![String::from("Aphex Twin"), String::from("Someone")],
+            album: None,
+            duration_ms: Some(367_000),
+            progress_ms: Some(61_000),
+            artwork_url: None,
+            url: String::from("https://example.com/track"),
+            source: String::from("spotify"),
+        }
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        let text = render(
+            "{artist} - {title} [{progress}/{duration}] {url} for {requester}",
+            Some(&track()),
+            "viewer",
+        )
+        .unwrap();
+
+        assert_eq!(
+            text,
+            "Aphex Twin, Someone - Windowlicker [1:01/6:07] https://example.com/track for viewer"
+        );
+    }
+
+    #[test]
+    fn missing_values_render_empty() {
+        assert_eq!(render("[{album}]", Some(&track()), "").unwrap(), "[]");
+        assert_eq!(
+            render("{title} by {requester}", None, "viewer").unwrap(),
+            " by viewer"
+        );
+    }
+
+    #[test]
+    fn renders_text_without_placeholders() {
+        assert_eq!(render("", None, "").unwrap(), "");
+        assert_eq!(
+            render("nothing playing", None, "").unwrap(),
+            "nothing playing"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders() {
+        let err = validate("{artist} - {song}").unwrap_err();
+
+        assert!(matches!(err.kind, ErrorKind::ConfigError));
+        assert!(err.message.contains("{song}"));
+        assert!(render("{song}", Some(&track()), "").is_err());
+        assert!(validate("{}").is_err());
+    }
+
+    #[test]
+    fn rejects_unclosed_placeholders() {
+        let err = validate("{artist} - {title").unwrap_err();
+
+        assert!(matches!(err.kind, ErrorKind::ConfigError));
+        assert!(err.message.contains("Unclosed"));
+        assert!(validate("{").is_err());
+    }
+
+    #[test]
+    fn formats_durations() {
+        assert_eq!(format_ms(0), "0:00");
+        assert_eq!(format_ms(59_999), "0:59");
+        assert_eq!(format_ms(3_723_000), "1:02:03");
+    }
+}
@@ -0,0 +1,406 @@
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::errors::{Error, ErrorKind, NPResult};
+use crate::Config;
+
+const PLAIN_FILE: &str = "tokens.json";
+const ENCRYPTED_FILE: &str = "tokens.enc";
+const PBKDF2_ROUNDS: u32 = 600_000;
+
+// deriving the key takes long on purpose, so the last one is kept for the following loads and saves
+static DERIVED_KEY: Mutex<Option<DerivedKey>> = Mutex::new(None);
+
+struct DerivedKey {
+    // sha256 of the passphrase, the passphrase itself is not kept
+    passphrase: [u8; 32],
+    salt: Vec<u8>,
+    key: [u8; 32],
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenStoreBackend {
+    // tokens.json next to the config, readable only by the owner
+    #[default]
+    Plain,
+    // tokens.enc next to the config, keyed by TOKEN_STORE_PASSPHRASE or key_file
+    Encrypted,
+    // secrets are only read from the environment and never written
+    Env,
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct TokenStoreConfig {
+    #[serde(default)]
+    pub backend: TokenStoreBackend,
+    #[serde(default)]
+    pub key_file: Option<String>,
+}
+
+// names of the Secrets fields, never logged, channels use the same names
+pub const SECRET_FIELDS: &[&str] = &[
+    "twitch_client_secret",
+    "twitch_oauth",
+    "twitch_oauth_refresh",
+    "bot_oauth",
+    "bot_oauth_refresh",
+    "spotify_client_secret",
+    "spotify_oauth",
+    "spotify_oauth_refresh",
+    "soundcloud_oauth",
+];
+
+// every config field that must not end up in the config file
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct Secrets {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub twitch_client_secret: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub twitch_oauth: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub twitch_oauth_refresh: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bot_oauth: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bot_oauth_refresh: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spotify_client_secret: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spotify_oauth: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spotify_oauth_refresh: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub soundcloud_oauth: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub channels: BTreeMap<String, ChannelSecrets>,
+}
+
+// the secrets a channel overrides, see channels::ChannelConfig
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct ChannelSecrets {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spotify_client_secret: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spotify_oauth: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spotify_oauth_refresh: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub soundcloud_oauth: Option<String>,
+}
+
+impl Secrets {
+    // moves the secrets out of the config, leaving it safe to write
+    pub fn take(config: &mut Config) -> Self {
+        let twitch_client_secret = std::mem::take(&mut config.twitch_client_secret);
+
+        Self {
+            twitch_client_secret: Some(twitch_client_secret).filter(|s| !s.is_empty()),
+            twitch_oauth: config.twitch_oauth.take(),
+            twitch_oauth_refresh: config.twitch_oauth_refresh.take(),
+            bot_oauth: config.bot_oauth.take(),
+            bot_oauth_refresh: config.bot_oauth_refresh.take(),
+            spotify_client_secret: config.spotify_client_secret.take(),
+            spotify_oauth: config.spotify_oauth.take(),
+            spotify_oauth_refresh: config.spotify_oauth_refresh.take(),
+            soundcloud_oauth: config.soundcloud_oauth.take(),
+            channels: config
+                .channels
+                .iter_mut()
+                .map(|(name, channel)| {
+                    let secrets = ChannelSecrets {
+                        spotify_client_secret: channel.spotify_client_secret.take(),
+                        spotify_oauth: channel.spotify_oauth.take(),
+                        spotify_oauth_refresh: channel.spotify_oauth_refresh.take(),
+                        soundcloud_oauth: channel.soundcloud_oauth.take(),
+                    };
+                    (name.clone(), secrets)
+                })
+                .filter(|(_, secrets)| !secrets.is_empty())
+                .collect(),
+        }
+    }
+
+    // values already in the config win, so older config files keep working until the next save
+    pub fn merge_into(self, config: &mut Config) {
+        if config.twitch_client_secret.is_empty() {
+            config.twitch_client_secret = self.twitch_client_secret.unwrap_or_default();
+        }
+        fill(&mut config.twitch_oauth, self.twitch_oauth);
+        fill(&mut config.twitch_oauth_refresh, self.twitch_oauth_refresh);
+        fill(&mut config.bot_oauth, self.bot_oauth);
+        fill(&mut config.bot_oauth_refresh, self.bot_oauth_refresh);
+        fill(
+            &mut config.spotify_client_secret,
+            self.spotify_client_secret,
+        );
+        fill(&mut config.spotify_oauth, self.spotify_oauth);
+        fill(
+            &mut config.spotify_oauth_refresh,
+            self.spotify_oauth_refresh,
+        );
+        fill(&mut config.soundcloud_oauth, self.soundcloud_oauth);
+
+        // secrets of channels that were removed from the config are dropped on the next save
+        for (name, secrets) in self.channels {
+            if let Some(channel) = config.channels.get_mut(&name) {
+                fill(
+                    &mut channel.spotify_client_secret,
+                    secrets.spotify_client_secret,
+                );
+                fill(&mut channel.spotify_oauth, secrets.spotify_oauth);
+                fill(
+                    &mut channel.spotify_oauth_refresh,
+                    secrets.spotify_oauth_refresh,
+                );
+                fill(&mut channel.soundcloud_oauth, secrets.soundcloud_oauth);
+            }
+        }
+    }
+}
+
+impl ChannelSecrets {
+    fn is_empty(&self) -> bool {
+        self.spotify_client_secret.is_none()
+            && self.spotify_oauth.is_none()
+            && self.spotify_oauth_refresh.is_none()
+            && self.soundcloud_oauth.is_none()
+    }
+}
+
+fn fill(field: &mut Option<String>, value: Option<String>) {
+    if field.is_none() {
+        *field = value;
+    }
+}
+
+pub trait TokenStore: Send + Sync {
+    fn load(&self) -> NPResult<Secrets>;
+    fn save(&self, secrets: &Secrets) -> NPResult<()>;
+}
+
+// dir is the directory of the config file, see Config::dir
+pub fn open(config: &TokenStoreConfig, dir: &Path) -> Box<dyn TokenStore> {
+    match config.backend {
+        TokenStoreBackend::Plain => Box::new(PlainFileStore {
+            path: dir.join(PLAIN_FILE),
+        }),
+        TokenStoreBackend::Encrypted => Box::new(EncryptedFileStore {
+            path: dir.join(ENCRYPTED_FILE),
+            key_file: config.key_file.clone(),
+        }),
+        TokenStoreBackend::Env => Box::new(EnvStore),
+    }
+}
+
+pub struct PlainFileStore {
+    path: PathBuf,
+}
+
+impl TokenStore for PlainFileStore {
+    fn load(&self) -> NPResult<Secrets> {
+        match std::fs::read(&self.path) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Secrets::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, secrets: &Secrets) -> NPResult<()> {
+        write_private(
+            &self.path,
+            serde_json::to_string_pretty(secrets)?.as_bytes(),
+        )
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct EncryptedFile {
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+pub struct EncryptedFileStore {
+    path: PathBuf,
+    key_file: Option<String>,
+}
+
+impl EncryptedFileStore {
+    fn passphrase(&self) -> NPResult<Vec<u8>> {
+        if let Ok(passphrase) = std::env::var("TOKEN_STORE_PASSPHRASE") {
+            return Ok(passphrase.into_bytes());
+        }
+
+        match &self.key_file {
+            Some(key_file) => Ok(std::fs::read(key_file)?),
+            None => Err(Error::new(
+                String::from("The encrypted token store needs TOKEN_STORE_PASSPHRASE or token_store.key_file"),
+                ErrorKind::ConfigError,
+            )),
+        }
+    }
+
+    // salt None reuses the salt of the cached key, or picks a new one
+    fn cipher(&self, salt: Option<&[u8]>) -> NPResult<(Aes256Gcm, Vec<u8>)> {
+        let passphrase = self.passphrase()?;
+        let digest: [u8; 32] = Sha256::digest(&passphrase).into();
+
+        let mut cached = DERIVED_KEY.lock().unwrap();
+        if let Some(derived) = cached.as_ref() {
+            if derived.passphrase == digest
+                && (salt.is_none() || salt == Some(derived.salt.as_slice()))
+            {
+                return Ok((Aes256Gcm::new(&derived.key.into()), derived.salt.clone()));
+            }
+        }
+
+        let salt = match salt {
+            Some(salt) => salt.to_vec(),
+            None => {
+                let mut salt = [0u8; 16];
+                OsRng.fill_bytes(&mut salt);
+                salt.to_vec()
+            }
+        };
+
+        let mut key = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(&passphrase, &salt, PBKDF2_ROUNDS, &mut key);
+
+        *cached = Some(DerivedKey {
+            passphrase: digest,
+            salt: salt.clone(),
+            key,
+        });
+
+        Ok((Aes256Gcm::new(&key.into()), salt))
+    }
+}
+
+impl TokenStore for EncryptedFileStore {
+    fn load(&self) -> NPResult<Secrets> {
+        let data = match std::fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Secrets::default()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let file: EncryptedFile = serde_json::from_slice(&data)?;
+        let decode = |s: &str| {
+            general_purpose::STANDARD
+                .decode(s)
+                .map_err(|e| Error::new(e.to_string(), ErrorKind::ParseError))
+        };
+
+        let nonce = decode(&file.nonce)?;
+        if nonce.len() != 12 {
+            return Err(Error::new(
+                format!("{} is corrupted", self.path.display()),
+                ErrorKind::ParseError,
+            ));
+        }
+
+        let (cipher, _) = self.cipher(Some(&decode(&file.salt)?))?;
+        let plaintext = cipher
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                decode(&file.ciphertext)?.as_slice(),
+            )
+            .map_err(|_| {
+                Error::new(
+                    format!(
+                        "Failed to decrypt {}, wrong passphrase?",
+                        self.path.display()
+                    ),
+                    ErrorKind::ConfigError,
+                )
+            })?;
+
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+
+    fn save(&self, secrets: &Secrets) -> NPResult<()> {
+        // every save gets a fresh nonce, so the salt and key can be reused
+        let (cipher, salt) = self.cipher(None)?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+        let ciphertext = cipher
+            .encrypt(&nonce, serde_json::to_vec(secrets)?.as_slice())
+            .map_err(|_| {
+                Error::new(
+                    String::from("Failed to encrypt tokens"),
+                    ErrorKind::UnknownError,
+                )
+            })?;
+
+        let file = EncryptedFile {
+            salt: general_purpose::STANDARD.encode(salt),
+            nonce: general_purpose::STANDARD.encode(nonce),
+            ciphertext: general_purpose::STANDARD.encode(ciphertext),
+        };
+
+        write_private(&self.path, serde_json::to_string_pretty(&file)?.as_bytes())
+    }
+}
+
+pub struct EnvStore;
+
+impl TokenStore for EnvStore {
+    fn load(&self) -> NPResult<Secrets> {
+        Ok(Secrets {
+            twitch_client_secret: std::env::var("TWITCH_CLIENT_SECRET").ok(),
+            twitch_oauth: std::env::var("TWITCH_OAUTH").ok(),
+            twitch_oauth_refresh: std::env::var("TWITCH_OAUTH_REFRESH").ok(),
+            bot_oauth: std::env::var("BOT_OAUTH").ok(),
+            bot_oauth_refresh: std::env::var("BOT_OAUTH_REFRESH").ok(),
+            spotify_client_secret: std::env::var("SPOTIFY_CLIENT_SECRET").ok(),
+            spotify_oauth: std::env::var("SPOTIFY_OAUTH").ok(),
+            spotify_oauth_refresh: std::env::var("SPOTIFY_OAUTH_REFRESH").ok(),
+            soundcloud_oauth: std::env::var("SOUNDCLOUD_OAUTH").ok(),
+            channels: BTreeMap::new(),
+        })
+    }
+
+    fn save(&self, _: &Secrets) -> NPResult<()> {
+        tracing::debug!("Not saving tokens, the token store is env only");
+        Ok(())
+    }
+}
+
+// replaces the file with one readable and writable only by the owner, it is written next to the
+// target and renamed over it so the config watcher or a crash never sees a half written file
+pub fn write_private(path: &Path, data: &[u8]) -> NPResult<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        options.mode(0o600);
+        if temp.exists() {
+            std::fs::set_permissions(&temp, std::fs::Permissions::from_mode(0o600))?;
+        }
+    }
+
+    let mut file = options.open(&temp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::rename(&temp, path)?;
+
+    Ok(())
+}
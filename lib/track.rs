@@ -0,0 +1,24 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct TrackMetadata {
+    pub title: String,
+    pub artists: Vec<String>,
+    pub album: Option<String>,
+    pub duration_ms: Option<u64>,
+    pub progress_ms: Option<u64>,
+    pub artwork_url: Option<String>,
+    pub url: String,
+    pub source: String,
+}
+
+impl TrackMetadata {
+    pub fn artist(&self) -> String {
+        self.artists.join(", ")
+    }
+
+    // progress changes on every fetch, so only compare what identifies the track
+    pub fn same_track(&self, other: &TrackMetadata) -> bool {
+        self.source == other.source && self.url == other.url
+    }
+}
@@ -0,0 +1,175 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, watch, Mutex};
+
+use crate::sources::SourceRegistry;
+use crate::track::TrackMetadata;
+use crate::Config;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TrackerConfig {
+    #[serde(default = "default_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+    // cached tracks older than this are fetched again when someone asks for them
+    #[serde(default = "default_ttl_secs")]
+    pub ttl_secs: u64,
+}
+
+fn default_poll_interval_secs() -> u64 {
+    10
+}
+
+fn default_ttl_secs() -> u64 {
+    30
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval_secs: default_poll_interval_secs(),
+            ttl_secs: default_ttl_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct CachedTrack {
+    track: Option<TrackMetadata>,
+    fetched_at: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct Tracker {
+    sources: SourceRegistry,
+    cache: Mutex<CachedTrack>,
+    changes: broadcast::Sender<TrackMetadata>,
+    // true once the sources have been asked at least once
+    polled: watch::Sender<bool>,
+    // unlike changes this also sees the song stop
+    playing: watch::Sender<Option<TrackMetadata>>,
+}
+
+impl Tracker {
+    pub fn new(sources: SourceRegistry) -> Self {
+        let (changes, _) = broadcast::channel(16);
+        let (polled, _) = watch::channel(false);
+        let (playing, _) = watch::channel(None);
+
+        Self {
+            sources,
+            cache: Mutex::new(CachedTrack::default()),
+            changes,
+            polled,
+            playing,
+        }
+    }
+
+    pub fn sources(&self) -> &SourceRegistry {
+        &self.sources
+    }
+
+    // receives every track that differs from the one before it
+    pub fn subscribe(&self) -> broadcast::Receiver<TrackMetadata> {
+        self.changes.subscribe()
+    }
+
+    pub async fn cached(&self) -> Option<TrackMetadata> {
+        self.cache.lock().await.track.clone()
+    }
+
+    // changes whenever a different song starts or the song stops
+    pub fn watch(&self) -> watch::Receiver<Option<TrackMetadata>> {
+        self.playing.subscribe()
+    }
+
+    // what was playing at the first poll, or now when that has already happened
+    pub async fn first_poll(&self) -> Option<TrackMetadata> {
+        let mut polled = self.polled.subscribe();
+
+        while !*polled.borrow_and_update() {
+            // the sender lives as long as the tracker
+            if polled.changed().await.is_err() {
+                break;
+            }
+        }
+
+        self.cached().await
+    }
+
+    pub async fn current(&self, ttl: Duration) -> Option<TrackMetadata> {
+        {
+            let cache = self.cache.lock().await;
+            if let Some(fetched_at) = cache.fetched_at {
+                if fetched_at.elapsed() < ttl {
+                    return cache.track.as_ref().map(|t| with_progress(t, fetched_at));
+                }
+            }
+        }
+
+        self.refresh().await
+    }
+
+    pub async fn refresh(&self) -> Option<TrackMetadata> {
+        let track = self.sources.now_playing().await;
+
+        let mut cache = self.cache.lock().await;
+
+        if let Some(track) = &track {
+            let changed = !cache
+                .track
+                .as_ref()
+                .is_some_and(|last| last.same_track(track));
+
+            if changed {
+                tracing::debug!("Track changed to {} - {}", track.artist(), track.title);
+                // nobody listening is fine
+                let _ = self.changes.send(track.clone());
+            }
+        }
+
+        cache.track = track.clone();
+        cache.fetched_at = Some(Instant::now());
+        drop(cache);
+
+        self.playing.send_if_modified(|playing| {
+            let changed = match (playing.as_ref(), track.as_ref()) {
+                (Some(last), Some(track)) => !last.same_track(track),
+                (None, None) => false,
+                _ => true,
+            };
+            if changed {
+                *playing = track.clone();
+            }
+            changed
+        });
+        self.polled.send_replace(true);
+
+        track
+    }
+
+    pub async fn run(self: Arc<Self>, config: Arc<Mutex<Config>>) {
+        loop {
+            self.refresh().await;
+
+            let interval = config.lock().await.tracker.poll_interval_secs.max(1);
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+        }
+    }
+}
+
+// progress was true when fetched, move it forward by the time spent in the cache
+fn with_progress(track: &TrackMetadata, fetched_at: Instant) -> TrackMetadata {
+    let mut track = track.clone();
+
+    if let Some(progress) = track.progress_ms {
+        let progress = progress + fetched_at.elapsed().as_millis() as u64;
+        track.progress_ms = Some(match track.duration_ms {
+            Some(duration) => progress.min(duration),
+            None => progress,
+        });
+    }
+
+    track
+}
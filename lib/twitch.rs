@@ -1,48 +1,218 @@
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
+use async_trait::async_trait;
+use chrono::{TimeZone, Utc};
 use hyper::StatusCode;
 use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::{Mutex, Notify};
-use twitch_irc::login::StaticLoginCredentials;
+use tokio::task::JoinHandle;
+use twitch_irc::login::{RefreshingLoginCredentials, TokenStorage, UserAccessToken};
 use twitch_irc::message::{PrivmsgMessage, ServerMessage};
 use twitch_irc::TwitchIRCClient;
 use twitch_irc::{ClientConfig, SecureTCPTransport};
 
+use crate::announcer;
+use crate::channels::Channel;
+use crate::commands::{CommandAction, Role};
+use crate::cooldown::CooldownKind;
 use crate::errors::{Error, ErrorKind, NPResult};
-use crate::{soundcloud, spotify, Config, ServerStatus};
+use crate::file_output;
+use crate::history;
+use crate::playback::{Playback, Vote};
+use crate::reload::{self, Reloader};
+use crate::requests::Rejection;
+use crate::sources::SourceRegistry;
+use crate::track::TrackMetadata;
+use crate::tracker::Tracker;
+use crate::{template, Config, ServerStatus};
 
 pub const TOKEN_URL: &str = "https://id.twitch.tv/oauth2/token";
 pub const CALLBACK_URI: &str = "http://localhost:3000/callback";
 
+// the access token is refreshed this long before it expires
+const REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+// leaves room for the "..." added to lists that do not fit
+const MAX_MESSAGE_LEN: usize = 490;
+
+pub type IrcClient =
+    TwitchIRCClient<SecureTCPTransport, RefreshingLoginCredentials<ConfigTokenStorage>>;
+
 #[derive(Debug, Clone)]
 pub struct TwitchClient {
     config: Arc<Mutex<Config>>,
-    client: Option<Arc<TwitchIRCClient<SecureTCPTransport, StaticLoginCredentials>>>,
-    soundcloud: Option<Arc<soundcloud::SoundcloudClient>>,
-    spotify: Option<Arc<Mutex<spotify::SpotifyClient>>>,
+    client: Option<Arc<IrcClient>>,
+    // shared by every channel without its own music settings
+    sources: SourceRegistry,
+    tracker: Arc<Tracker>,
+    channels: Arc<Mutex<HashMap<String, Joined>>>,
+    reloader: Arc<Reloader>,
+}
+
+#[derive(Debug)]
+struct AbortOnDrop(JoinHandle<()>);
+
+// dropping it stops the tasks of the channel
+#[derive(Debug)]
+struct Joined {
+    channel: Arc<Channel>,
+    _tasks: Vec<AbortOnDrop>,
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct AuthResponse {
     pub access_token: String,
-    pub expires_in: u64,
+    // seconds the access token is valid for, Twitch sends it for new and refreshed tokens alike
+    pub expires_in: Option<u64>,
     pub refresh_token: String,
     pub scope: Vec<String>,
     pub token_type: String,
 }
 
-pub trait Song: Send {
-    fn url(&self) -> String;
+#[derive(Debug, Clone, Deserialize)]
+pub struct ValidateResponse {
+    pub login: String,
+    pub expires_in: u64,
+}
+
+// the tokens chat logs in with, see Config::chat_tokens
+#[derive(Debug, Clone, Default)]
+pub struct ChatTokens {
+    pub oauth: Option<String>,
+    pub refresh: Option<String>,
+    // unix timestamp, None when unknown
+    pub expires_at: Option<i64>,
 }
 
+impl AuthResponse {
+    pub fn store(&self, config: &mut Config) {
+        config.set_chat_tokens(ChatTokens {
+            oauth: Some(self.access_token.clone()),
+            refresh: Some(self.refresh_token.clone()),
+            expires_at: self
+                .expires_in
+                .map(|secs| Utc::now().timestamp() + secs as i64),
+        });
+    }
+}
+
+// lets twitch-irc read the token from the config and save the tokens it refreshes itself
+#[derive(Debug)]
+pub struct ConfigTokenStorage {
+    config: Arc<Mutex<Config>>,
+}
+
+impl ConfigTokenStorage {
+    pub fn new(config: Arc<Mutex<Config>>) -> Self {
+        Self { config }
+    }
+}
+
+#[async_trait]
+impl TokenStorage for ConfigTokenStorage {
+    type LoadError = Error;
+    type UpdateError = Error;
+
+    async fn load_token(&mut self) -> Result<UserAccessToken, Self::LoadError> {
+        let tokens = self.config.lock().await.chat_tokens();
+
+        let missing = || Error::new(String::from("No OAuth token"), ErrorKind::TwitchError);
+
+        Ok(UserAccessToken {
+            access_token: tokens.oauth.ok_or_else(missing)?,
+            refresh_token: tokens.refresh.ok_or_else(missing)?,
+            created_at: Utc::now(),
+            expires_at: tokens
+                .expires_at
+                .and_then(|at| Utc.timestamp_opt(at, 0).single()),
+        })
+    }
+
+    async fn update_token(&mut self, token: &UserAccessToken) -> Result<(), Self::UpdateError> {
+        tracing::info!("Twitch IRC refreshed its OAuth token");
+
+        let snapshot = {
+            let mut conf = self.config.lock().await;
+            conf.set_chat_tokens(ChatTokens {
+                oauth: Some(token.access_token.clone()),
+                refresh: Some(token.refresh_token.clone()),
+                expires_at: token.expires_at.map(|at| at.timestamp()),
+            });
+            conf.clone()
+        };
+
+        snapshot.save().await
+    }
+}
+
+// force_verify lets whoever opens it pick the account, so the bot account can be authorized from
+// a browser logged in as the broadcaster
 pub fn make_oauth_url(client_id: &str, callback_uri: &str) -> String {
     format!(
-        "https://id.twitch.tv/oauth2/authorize?client_id={}&redirect_uri={}&response_type=code&scope=chat:read%20chat:edit",
+        "https://id.twitch.tv/oauth2/authorize?client_id={}&redirect_uri={}&response_type=code&scope=chat:read%20chat:edit&force_verify=true",
         client_id, callback_uri
     )
 }
 
+pub async fn validate_access_token(access_token: &str) -> NPResult<ValidateResponse> {
+    let response = reqwest::Client::new()
+        .get("https://id.twitch.tv/oauth2/validate")
+        .header("Authorization", format!("OAuth {}", access_token))
+        .send()
+        .await?;
+
+    match response.status() {
+        StatusCode::OK => Ok(response.json().await?),
+        _ => Err(Error::new(
+            format!("expected status 200, got {}", response.status()),
+            ErrorKind::HttpError,
+        )),
+    }
+}
+
+// numbers the entries after the heading, twitch cuts messages at 500 characters so the entries
+// that do not fit are left out
+fn numbered_list(heading: String, entries: impl Iterator<Item = String>) -> String {
+    let mut message = heading;
+
+    for (i, entry) in entries.enumerate() {
+        let entry = format!(" {}. {}", i + 1, entry);
+        if message.len() + entry.len() > MAX_MESSAGE_LEN {
+            message.push_str(" ...");
+            break;
+        }
+        message.push_str(&entry);
+    }
+
+    message
+}
+
+// tokens of another account would make the bot chat as that account
+pub fn check_login(config: &Config, login: &str) -> NPResult<()> {
+    let expected = config.chat_username();
+
+    if login.eq_ignore_ascii_case(&expected) {
+        return Ok(());
+    }
+
+    Err(Error::new(
+        format!(
+            "The Twitch token belongs to {}, but chat logs in as {}. Authorize Twitch while logged in as {}",
+            login, expected, expected
+        ),
+        ErrorKind::TwitchError,
+    ))
+}
+
 pub async fn exchange_code(config: Arc<Mutex<Config>>, code: String) -> NPResult<AuthResponse> {
     let client = reqwest::Client::new();
 
@@ -85,14 +255,16 @@ pub async fn exchange_code(config: Arc<Mutex<Config>>, code: String) -> NPResult
 impl TwitchClient {
     pub fn new(
         config: Arc<Mutex<Config>>,
-        soundcloud: Option<Arc<soundcloud::SoundcloudClient>>,
-        spotify: Option<Arc<Mutex<spotify::SpotifyClient>>>,
+        sources: SourceRegistry,
+        reloader: Arc<Reloader>,
     ) -> Self {
         Self {
             config,
             client: None,
-            soundcloud,
-            spotify,
+            sources: sources.clone(),
+            tracker: Arc::new(Tracker::new(sources)),
+            channels: Arc::new(Mutex::new(HashMap::new())),
+            reloader,
         }
     }
 
@@ -101,12 +273,21 @@ impl TwitchClient {
             return Ok(None);
         }
 
-        let config = self.config.lock().await;
+        self.force_refresh_oauth().await.map(Some)
+    }
 
-        let client_id = &config.twitch_client_id;
-        let client_secret = &config.twitch_client_secret;
+    pub async fn force_refresh_oauth(&self) -> NPResult<AuthResponse> {
+        // chat reads the config while the token endpoint answers, so it is not kept locked
+        let (client_id, client_secret, refresh) = {
+            let config = self.config.lock().await;
+            (
+                config.twitch_client_id.clone(),
+                config.twitch_client_secret.clone(),
+                config.chat_tokens().refresh,
+            )
+        };
         let grant_type = String::from("refresh_token");
-        let refresh_token = match config.twitch_oauth_refresh.clone() {
+        let refresh_token = match refresh {
             Some(token) => token,
             None => {
                 return Err(Error::new(
@@ -120,8 +301,8 @@ impl TwitchClient {
         let client = reqwest::Client::new();
 
         let mut form_data = HashMap::new();
-        form_data.insert("client_id", client_id);
-        form_data.insert("client_secret", client_secret);
+        form_data.insert("client_id", &client_id);
+        form_data.insert("client_secret", &client_secret);
         form_data.insert("grant_type", &grant_type);
         form_data.insert("refresh_token", &refresh_token);
 
@@ -138,7 +319,7 @@ impl TwitchClient {
 
                 tracing::info!("Refreshed Twitch OAuth");
 
-                Ok(Some(auth_response))
+                Ok(auth_response)
             }
             _ => Err(Error::new(
                 format!("expected status 200, got {}", response.status()),
@@ -149,30 +330,28 @@ impl TwitchClient {
     }
 
     pub async fn test_token(&self) -> NPResult<()> {
-        let config = self.config.lock().await;
+        self.validate_token().await.map(|_| ())
+    }
 
-        let client = reqwest::Client::new();
+    pub async fn validate_token(&self) -> NPResult<ValidateResponse> {
+        let oauth = match self.config.lock().await.chat_tokens().oauth {
+            Some(oauth) => oauth,
+            None => {
+                return Err(Error::new(
+                    String::from("No OAuth token"),
+                    ErrorKind::TwitchError,
+                ))
+            }
+        };
 
-        let response = client
-            .get("https://id.twitch.tv/oauth2/validate")
-            .header(
-                "Authorization",
-                format!("OAuth {}", match config.twitch_oauth.clone() {
-                    Some(oauth) => oauth,
-                    None => return Err(Error::new(String::from("No OAuth token"), ErrorKind::TwitchError).into()),
-                }),
-            )
-            .send()
-            .await?;
+        validate_access_token(&oauth).await
+    }
 
-        match response.status() {
-            StatusCode::OK => Ok(()),
-            _ => Err(Error::new(
-                format!("expected status 200, got {}", response.status()),
-                ErrorKind::HttpError,
-            )
-            .into()),
-        }
+    // whether the saved token belongs to the account chat logs in as
+    pub async fn check_account(&self) -> NPResult<()> {
+        let login = self.validate_token().await?.login;
+
+        check_login(&*self.config.lock().await, &login)
     }
 
     pub async fn listener(&mut self, status: Arc<(Mutex<ServerStatus>, Notify)>) -> NPResult<()> {
@@ -180,30 +359,47 @@ impl TwitchClient {
 
         let conf = self.config.lock().await.clone();
 
-        let credentials = StaticLoginCredentials::new(
-            conf.twitch_username.clone(),
-            conf.twitch_oauth.clone(),
+        // reconnects pick up refreshed tokens through the storage
+        let credentials = RefreshingLoginCredentials::init(
+            conf.twitch_client_id.clone(),
+            conf.twitch_client_secret.clone(),
+            ConfigTokenStorage::new(self.config.clone()),
         );
 
         let config = ClientConfig::new_simple(credentials);
 
-        let (mut incoming_messages, client) =
-            TwitchIRCClient::<SecureTCPTransport, StaticLoginCredentials>::new(config);
+        let (mut incoming_messages, client) = IrcClient::new(config);
 
-        client
-            .join(conf.twitch_username.clone())
-            .expect("Failed to join channel");
+        let client = Arc::new(client);
+        self.client = Some(client.clone());
 
+        // aborted when the listener returns or is dropped by a restart
+        let _tracker = AbortOnDrop(tokio::spawn(self.tracker.clone().run(self.config.clone())));
+        let _file_output = AbortOnDrop(tokio::spawn(file_output::run(
+            self.config.clone(),
+            self.tracker.clone(),
+        )));
 
-        self.client = Some(Arc::new(client));
+        // the channel tasks stop when this client is dropped by a restart
+        self.sync_channels(&conf, &client, &[]).await;
 
         tracing::info!(
-            "Connected to Twitch IRC, joined channel {}",
-            conf.twitch_username.clone()
+            "Connected to Twitch IRC, joined {}",
+            conf.channel_names().join(", ")
         );
 
         let self_arc = Arc::new(self.clone());
 
+        let follower = self_arc.clone();
+        let _reloads = AbortOnDrop(tokio::spawn(async move {
+            follower.follow_reloads(client).await;
+        }));
+
+        let refresher = self_arc.clone();
+        let _refresher = AbortOnDrop(tokio::spawn(async move {
+            refresher.keep_token_fresh().await;
+        }));
+
         tracing::info!("Listening for messages");
         while let Some(message) = incoming_messages.recv().await {
             let self_ref = self_arc.clone();
@@ -228,6 +424,175 @@ impl TwitchClient {
         Ok(())
     }
 
+    // settings read on every message apply by themselves, this handles the ones held by running clients
+    async fn follow_reloads(&self, client: Arc<IrcClient>) {
+        let mut changes = self.reloader.subscribe();
+
+        loop {
+            let changed = match changes.recv().await {
+                Ok(changed) => changed,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+
+            let conf = self.config.lock().await.clone();
+
+            reload::apply_to_sources(&self.config, &conf, &self.sources, &changed);
+
+            if changed
+                .iter()
+                .any(|c| c == "twitch_username" || c == "channel" || c.starts_with("channels"))
+                || reload::touches_sources(&changed)
+            {
+                self.sync_channels(&conf, &client, &changed).await;
+            }
+
+            if changed.iter().any(|c| {
+                c == "twitch_client_id" || c == "twitch_client_secret" || c == "bot_username"
+            }) {
+                tracing::warn!(
+                    "The Twitch client id, secret and bot account only change after a restart"
+                );
+            }
+        }
+    }
+
+    // joins and leaves channels to match the config, channels whose settings are in changed
+    // start over with new sources
+    async fn sync_channels(&self, conf: &Config, client: &Arc<IrcClient>, changed: &[String]) {
+        let names = conf.channel_names();
+        let shared_changed = reload::touches_sources(changed);
+
+        let mut channels = self.channels.lock().await;
+
+        channels.retain(|name, _| {
+            let keep = names.contains(name);
+            if !keep {
+                tracing::info!("Leaving channel {}", name);
+            }
+            keep
+        });
+
+        for name in &names {
+            let prefix = format!("channels.{}", name);
+            let stale = match channels.get(name) {
+                Some(joined) => {
+                    changed
+                        .iter()
+                        .any(|c| c == &prefix || c.starts_with(&format!("{}.", prefix)))
+                        || (shared_changed && joined.channel.has_own_sources())
+                }
+                None => {
+                    tracing::info!("Joining channel {}", name);
+                    true
+                }
+            };
+
+            if !stale {
+                continue;
+            }
+
+            let mut channel = Channel::new(name, &self.config, conf, &self.tracker);
+            // requests already on the queue, the play history and the session survive a settings change
+            if let Some(joined) = channels.get(name) {
+                channel.requests = joined.channel.requests.clone();
+                channel.history = joined.channel.history.clone();
+                channel.session = joined.channel.session.clone();
+            }
+            let channel = Arc::new(channel);
+
+            let mut tasks = Vec::new();
+            if channel.has_own_sources() {
+                tasks.push(AbortOnDrop(tokio::spawn(
+                    channel.tracker.clone().run(self.config.clone()),
+                )));
+            }
+            tasks.push(AbortOnDrop(tokio::spawn(announcer::run(
+                self.config.clone(),
+                channel.tracker.clone(),
+                client.clone(),
+                name.clone(),
+            ))));
+            tasks.push(AbortOnDrop(tokio::spawn(
+                channel.requests.clone().run(channel.tracker.clone()),
+            )));
+            tasks.push(AbortOnDrop(tokio::spawn(channel.history.clone().run(
+                self.config.clone(),
+                channel.tracker.clone(),
+                name.clone(),
+            ))));
+            tasks.push(AbortOnDrop(tokio::spawn(
+                channel.session.clone().run(channel.tracker.clone()),
+            )));
+
+            channels.insert(
+                name.clone(),
+                Joined {
+                    channel,
+                    _tasks: tasks,
+                },
+            );
+        }
+
+        if let Err(e) = client.set_wanted_channels(names.into_iter().collect()) {
+            tracing::error!("Failed to join channels: {}", e);
+        }
+    }
+
+    async fn keep_token_fresh(&self) {
+        loop {
+            let expires_at = self.config.lock().await.chat_tokens().expires_at;
+
+            let expires_at = match expires_at {
+                Some(at) => at,
+                // tokens from an older config do not say when they expire
+                None => match self.validate_token().await {
+                    Ok(v) => {
+                        let at = Utc::now().timestamp() + v.expires_in as i64;
+                        let mut conf = self.config.lock().await;
+                        let tokens = conf.chat_tokens();
+                        conf.set_chat_tokens(ChatTokens {
+                            expires_at: Some(at),
+                            ..tokens
+                        });
+                        at
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to validate Twitch OAuth: {}", e);
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                        continue;
+                    }
+                },
+            };
+
+            let remaining = (expires_at - Utc::now().timestamp()).max(0) as u64;
+            let remaining = Duration::from_secs(remaining);
+            tokio::time::sleep(remaining.saturating_sub(REFRESH_MARGIN)).await;
+
+            // twitch-irc may have refreshed the token while we were waiting
+            if self.config.lock().await.chat_tokens().expires_at != Some(expires_at) {
+                continue;
+            }
+
+            match self.force_refresh_oauth().await {
+                Ok(auth) => {
+                    let snapshot = {
+                        let mut conf = self.config.lock().await;
+                        auth.store(&mut conf);
+                        conf.clone()
+                    };
+                    if let Err(e) = snapshot.save().await {
+                        tracing::error!("Failed to save refreshed Twitch token. {}", e);
+                    }
+                }
+                Err(e) => {
+                    tracing::error!("Failed to refresh Twitch OAuth: {}", e);
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                }
+            }
+        }
+    }
+
     pub async fn handler(&self, msg: ServerMessage) {
         match msg {
             ServerMessage::Privmsg(msg) => self.message_handler(msg).await,
@@ -236,58 +601,388 @@ impl TwitchClient {
     }
 
     pub async fn message_handler(&self, msg: PrivmsgMessage) {
-        match msg.message_text.as_str() {
-            "!np" => self.now_playing(msg).await,
-            "!song" => self.now_playing(msg).await,
-            _ => {}
+        let channel = match self.channels.lock().await.get(&msg.channel_login) {
+            Some(joined) => joined.channel.clone(),
+            // left while the message was on its way
+            None => return,
+        };
+
+        let (command, cooldowns, denied_reply) = {
+            let conf = self.config.lock().await.for_channel(&channel.name);
+            match conf.commands.parse(&msg.message_text) {
+                Some(command) => (
+                    command,
+                    conf.cooldowns.clone(),
+                    conf.commands.permission_denied_reply.clone(),
+                ),
+                None => return,
+            }
+        };
+
+        tracing::debug!("{} used {}", msg.sender.login, command.trigger);
+
+        let role = Role::from_badges(&msg.badges);
+        if role < command.permission {
+            tracing::debug!(
+                "Ignoring {} from {}, requires {:?} but they are {:?}",
+                command.trigger,
+                msg.sender.login,
+                command.permission,
+                role
+            );
+
+            let reply = !denied_reply.is_empty()
+                && channel
+                    .cooldowns
+                    .lock()
+                    .await
+                    .try_deny(&cooldowns, &msg.sender.login);
+            if reply {
+                match template::render(&denied_reply, None, &msg.sender.name) {
+                    Ok(message) => self.reply(&msg, message).await,
+                    Err(e) => tracing::error!("Failed to render reply: {}", e),
+                }
+            }
+            return;
         }
-    }
+        let allowed = channel.cooldowns.lock().await.try_use(
+            &cooldowns,
+            command.action,
+            &msg.sender.login,
+            role,
+        );
 
-    pub async fn now_playing(&self, origin: PrivmsgMessage) {
-        let mut song: Option<Box<dyn Song>> = None;
+        if let Err((kind, remaining)) = allowed {
+            let kind = match kind {
+                CooldownKind::Global => "global",
+                CooldownKind::User => "user",
+            };
+            tracing::debug!(
+                "Ignoring {} from {}, {} cooldown active for another {}s",
+                command.trigger,
+                msg.sender.login,
+                kind,
+                remaining.as_secs() + 1
+            );
+            return;
+        }
 
-        if let Some(spotify) = &self.spotify {
-            match spotify.lock().await.fetch_current_song().await {
-                Ok(track) => {
-                    if let Some(track) = track {
-                        song = Some(Box::new(track) as Box<dyn Song>);
+        match command.action {
+            CommandAction::NowPlaying => self.now_playing(msg, &channel).await,
+            CommandAction::SongRequest => {
+                let query = command.args.join(" ");
+                self.song_request(msg, &channel, &command.trigger, &query, role)
+                    .await
+            }
+            CommandAction::Queue => self.queue(msg, &channel).await,
+            CommandAction::Skip => self.playback(msg, &channel, Playback::Skip).await,
+            CommandAction::Pause => self.playback(msg, &channel, Playback::Pause).await,
+            CommandAction::Resume => self.playback(msg, &channel, Playback::Resume).await,
+            CommandAction::Volume => {
+                let volume = command
+                    .args
+                    .first()
+                    .and_then(|v| v.trim_end_matches('%').parse::<u8>().ok())
+                    .filter(|v| *v <= 100);
+
+                match volume {
+                    Some(volume) => self.playback(msg, &channel, Playback::Volume(volume)).await,
+                    None => {
+                        let conf = self.config.lock().await.for_channel(&channel.name);
+                        let prefix = conf.commands.prefix;
+                        let usage = format!("Usage: {}{} <0-100>", prefix, command.trigger);
+                        self.reply(&msg, usage).await;
                     }
                 }
-                Err(e) => {
-                    tracing::error!("{}", e);
-                    song = None;
+            }
+            CommandAction::VoteSkip => self.vote_skip(msg, &channel).await,
+            CommandAction::LastSong => self.last_song(msg, &channel).await,
+            CommandAction::History => {
+                let count = command.args.first().and_then(|n| n.parse::<usize>().ok());
+                self.history(msg, &channel, count).await
+            }
+        }
+    }
+
+    pub async fn last_song(&self, origin: PrivmsgMessage, channel: &Channel) {
+        let template = self
+            .config
+            .lock()
+            .await
+            .for_channel(&channel.name)
+            .history
+            .last_song_template;
+
+        let playing = channel.tracker.cached().await;
+        let last = match channel.history.last_song(playing.as_ref()).await {
+            Some(last) => last,
+            None => {
+                self.reply(&origin, String::from("No song has been played yet."))
+                    .await;
+                return;
+            }
+        };
+
+        if template.is_empty() {
+            return;
+        }
+
+        match template::render(&template, Some(&last.track), &origin.sender.name) {
+            Ok(message) => self.reply(&origin, message).await,
+            Err(e) => tracing::error!("Failed to render reply: {}", e),
+        }
+    }
+
+    pub async fn history(&self, origin: PrivmsgMessage, channel: &Channel, count: Option<usize>) {
+        let conf = self.config.lock().await.for_channel(&channel.name).history;
+        let count = count.unwrap_or(conf.count).max(1);
+
+        let recent = channel.history.recent(count).await;
+
+        if recent.is_empty() {
+            self.reply(&origin, String::from("No song has been played yet."))
+                .await;
+            return;
+        }
+
+        let message = numbered_list(
+            String::from("Recently played:"),
+            recent.iter().map(|entry| {
+                format!(
+                    "{} - {} ({})",
+                    entry.track.artist(),
+                    entry.track.title,
+                    history::ago(entry.played_at)
+                )
+            }),
+        );
+
+        self.reply(&origin, message).await;
+    }
+
+    pub async fn playback(&self, origin: PrivmsgMessage, channel: &Channel, action: Playback) {
+        let message = self.control(channel, action, &origin.sender.login).await;
+        self.reply(&origin, message).await;
+    }
+
+    pub async fn vote_skip(&self, origin: PrivmsgMessage, channel: &Channel) {
+        let (conf, ttl) = {
+            let conf = self.config.lock().await;
+            (
+                conf.for_channel(&channel.name).vote_skip,
+                Duration::from_secs(conf.tracker.ttl_secs),
+            )
+        };
+
+        let song = match channel.tracker.current(ttl).await {
+            Some(song) => song,
+            None => {
+                self.reply_song(&origin, channel, None).await;
+                return;
+            }
+        };
+
+        let vote = channel
+            .votes
+            .lock()
+            .await
+            .vote(&conf, &origin.sender.login, Some(&song));
+
+        let message = match vote {
+            Vote::Counted { votes, needed } => {
+                tracing::info!(
+                    "{} voted to skip in {} ({}/{})",
+                    origin.sender.login,
+                    channel.name,
+                    votes,
+                    needed
+                );
+                format!("Vote to skip: {}/{}", votes, needed)
+            }
+            Vote::AlreadyVoted { votes, needed } => {
+                format!("You already voted to skip: {}/{}", votes, needed)
+            }
+            Vote::Passed => {
+                tracing::info!("Vote to skip passed in {}", channel.name);
+                self.control(channel, Playback::Skip, "vote").await
+            }
+        };
+
+        self.reply(&origin, message).await;
+    }
+
+    // returns the reply for chat
+    async fn control(&self, channel: &Channel, action: Playback, by: &str) -> String {
+        let source = match channel.tracker.sources().playback_source() {
+            Some(source) => source,
+            None => return String::from("Playback can't be controlled right now."),
+        };
+
+        match source.control(action).await {
+            Ok(()) => {
+                tracing::info!("{:?} in {} by {}", action, channel.name, by);
+                if action == Playback::Skip {
+                    channel.votes.lock().await.reset();
                 }
+                action.to_string()
+            }
+            Err(e) => {
+                tracing::error!("Failed {:?} in {}: {}", action, channel.name, e);
+                format!("Couldn't control playback. {}", e.message)
             }
+        }
+    }
+
+    pub async fn song_request(
+        &self,
+        origin: PrivmsgMessage,
+        channel: &Channel,
+        trigger: &str,
+        query: &str,
+        role: Role,
+    ) {
+        let (conf, prefix) = {
+            let conf = self.config.lock().await.for_channel(&channel.name);
+            (conf.song_requests, conf.commands.prefix)
         };
 
-        if song.is_none() {
-            if let Some(sc) = &self.soundcloud {
-                match sc.fetch_current_song().await {
-                    Ok(track) => {
-                        song = Some(Box::new(track) as Box<dyn Song>);
-                    }
+        if !conf.enabled {
+            return;
+        }
+
+        if query.trim().is_empty() {
+            let usage = format!("Usage: {}{} <Spotify link or search>", prefix, trigger);
+            self.reply(&origin, usage).await;
+            return;
+        }
+
+        let requested = channel
+            .requests
+            .request(
+                &conf,
+                channel.tracker.sources(),
+                query,
+                &origin.sender.login,
+                role,
+            )
+            .await;
+
+        let message = match requested {
+            Ok(request) => {
+                tracing::info!(
+                    "{} requested {} - {} in {}",
+                    origin.sender.login,
+                    request.track.artist(),
+                    request.track.title,
+                    channel.name
+                );
+
+                match template::render(
+                    &conf.added_template,
+                    Some(&request.track),
+                    &origin.sender.name,
+                ) {
+                    Ok(message) => message,
                     Err(e) => {
-                        tracing::error!("{}", e);
-                        song = None;
+                        tracing::error!("Failed to render reply: {}", e);
+                        return;
                     }
                 }
             }
+            Err(rejection) => {
+                if let Rejection::Failed(e) = &rejection {
+                    tracing::error!("Failed to queue a song request: {}", e);
+                }
+                rejection.to_string()
+            }
         };
 
-        match song {
-            Some(song) => {
-                let message = format!("Now playing: {}", song.url());
+        if !message.is_empty() {
+            self.reply(&origin, message).await;
+        }
+    }
 
-                if let Some(client) = self.client.as_ref() {
-                    if let Err(e) = client.say_in_reply_to(&origin, message).await {
-                        tracing::error!("Failed to send message: {:?}", e);
-                    }
-                }
+    pub async fn queue(&self, origin: PrivmsgMessage, channel: &Channel) {
+        if !self
+            .config
+            .lock()
+            .await
+            .for_channel(&channel.name)
+            .song_requests
+            .enabled
+        {
+            return;
+        }
+
+        let pending = channel.requests.pending().await;
+
+        if pending.is_empty() {
+            self.reply(&origin, String::from("The request queue is empty."))
+                .await;
+            return;
+        }
+
+        let message = numbered_list(
+            format!("{} in the queue:", pending.len()),
+            pending.iter().map(|request| {
+                format!(
+                    "{} - {} ({})",
+                    request.track.artist(),
+                    request.track.title,
+                    request.requester
+                )
+            }),
+        );
+
+        self.reply(&origin, message).await;
+    }
+
+    pub async fn now_playing(&self, origin: PrivmsgMessage, channel: &Channel) {
+        let ttl = Duration::from_secs(self.config.lock().await.tracker.ttl_secs);
+        let song = channel.tracker.current(ttl).await;
+
+        if song.is_none() {
+            tracing::info!("No song found playing in {}.", channel.name);
+        }
+
+        self.reply_song(&origin, channel, song.as_ref()).await;
+    }
+
+    // the now playing or nothing playing template, an empty one sends nothing
+    async fn reply_song(
+        &self,
+        origin: &PrivmsgMessage,
+        channel: &Channel,
+        song: Option<&TrackMetadata>,
+    ) {
+        let template = {
+            let conf = self.config.lock().await.for_channel(&channel.name);
+            match song {
+                Some(_) => conf.now_playing_template.clone(),
+                None => conf.nothing_playing_template.clone(),
             }
-            None => {
-                tracing::info!("No song found playing.");
+        };
+
+        if template.is_empty() {
+            return;
+        }
+
+        let message = match template::render(&template, song, &origin.sender.name) {
+            Ok(m) => m,
+            Err(e) => {
+                tracing::error!("Failed to render reply: {}", e);
                 return;
             }
         };
+
+        self.reply(origin, message).await;
+    }
+
+    async fn reply(&self, origin: &PrivmsgMessage, message: String) {
+        if let Some(client) = self.client.as_ref() {
+            if let Err(e) = client.say_in_reply_to(origin, message).await {
+                tracing::error!("Failed to send message: {:?}", e);
+            }
+        }
     }
 }
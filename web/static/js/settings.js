@@ -62,6 +62,8 @@ async function updateSettingsFields(settings) {
     document.getElementById("twitch_client_id").value = settings.twitch_client_id;
     document.getElementById("twitch_client_secret").value = settings.twitch_client_secret;
     document.getElementById("twitch_username").value = settings.twitch_username;
+    document.getElementById("bot_username").value = settings.bot_username ?? "";
+    document.getElementById("channel").value = settings.channel ?? "";
 
     if (settings.soundcloud_enabled) {
         document.getElementById("soundcloud_enabled").checked = settings.soundcloud_enabled;
@@ -88,6 +90,14 @@ async function updateSettingsFields(settings) {
         settings.twitch_username = this.value;
     });
 
+    document.querySelector("#bot_username").addEventListener("change", function() {
+        settings.bot_username = this.value || null;
+    });
+
+    document.querySelector("#channel").addEventListener("change", function() {
+        settings.channel = this.value || null;
+    });
+
     document.querySelector("#soundcloud_enabled").addEventListener("click", function() {
         settings.soundcloud_enabled = !settings.soundcloud_enabled;
         document.getElementById("soundcloud_enabled").checked = settings.soundcloud_enabled;